@@ -0,0 +1,34 @@
+use std::{env, fs, path::PathBuf};
+
+/// Exposes the version of the nix-index crate as locked in Cargo.lock to the
+/// crate as `NIX_INDEX_VERSION`
+fn main() {
+    let lockfile = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+    let version = fs::read_to_string(&lockfile)
+        .ok()
+        .and_then(|lock| locked_version(&lock, "nix-index"))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=NIX_INDEX_VERSION={}", version);
+}
+
+/// Returns the locked version of a package, with the abbreviated revision for
+/// git dependencies, e.g. `0.1.6 (a8ceb75)`
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", name);
+    let package = lock
+        .split("[[package]]")
+        .find(|p| p.lines().any(|l| l == name_line))?;
+    let field = |key: &str| {
+        package.lines().find_map(|l| {
+            l.strip_prefix(key)
+                .and_then(|v| v.strip_prefix(" = \""))
+                .map(|v| v.trim_end_matches('"'))
+        })
+    };
+    let version = field("version")?;
+    match field("source").and_then(|s| s.strip_prefix("git+")?.rsplit_once('#')) {
+        Some((_, rev)) => Some(format!("{} ({})", version, &rev[..rev.len().min(7)])),
+        None => Some(version.to_string()),
+    }
+}
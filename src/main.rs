@@ -1,27 +1,126 @@
 use std::{
     collections::HashMap,
+    convert::TryFrom,
     fs,
     io::{self, prelude::*},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process::Command,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use indicatif::{
-    ParallelProgressIterator, ProgressBar, ProgressFinish, ProgressIterator, ProgressStyle,
-};
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches};
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
 use regex::bytes::Regex;
+use tracing_subscriber::filter::LevelFilter;
 
-const NIX_BUILD_FHS: &str = "nix-build --no-out-link -E";
 const LDD_NOT_FOUND: &str = " => not found";
+const LDD_MUSL_ERROR: &str = "Error loading shared library ";
+/// sonames as they are passed to `dlopen`, e.g. `libvulkan.so.1`
+const DLOPEN_PATTERN: &str =
+    r"(?-u)\b(lib[A-Za-z0-9_+-]+(?:\.[A-Za-z0-9_+-]+)*\.so(?:\.[0-9]+)*)\b";
+
+/// sonames every FHS provides through its base packages, glibc and
+/// `gcc.cc.lib`, which thus need not be resolved
+const FHS_BASE_LIBS: &[&str] = &[
+    "ld-linux-x86-64.so.2",
+    "ld-linux-aarch64.so.1",
+    "ld-linux.so.2",
+    "libc.so.6",
+    "libdl.so.2",
+    "libm.so.6",
+    "libmvec.so.1",
+    "libnsl.so.1",
+    "libpthread.so.0",
+    "libresolv.so.2",
+    "librt.so.1",
+    "libutil.so.1",
+    "libgcc_s.so.1",
+    "libstdc++.so.6",
+];
+/// compiler runtime libs, which nix-index finds in many packages, mapped to
+/// the one reliably providing them. `libgcc_s.so.1` and `libstdc++.so.6` are
+/// in [`FHS_BASE_LIBS`] too, so their mapping only applies with
+/// `--no-skip-base`.
+const BUILTIN_MAPPINGS: &[(&str, &str)] = &[
+    ("libgcc_s.so.1", "stdenv.cc.cc.lib"),
+    ("libstdc++.so.6", "stdenv.cc.cc.lib"),
+    ("libgomp.so.1", "stdenv.cc.cc.lib"),
+    ("libatomic.so.1", "stdenv.cc.cc.lib"),
+    ("libquadmath.so.0", "stdenv.cc.cc.lib"),
+    ("libgfortran.so.5", "gfortran.cc.lib"),
+];
+
+/// number of unresolved libs from which on steam-run is suggested
+const STEAM_RUN_HINT_THRESHOLD: usize = 5;
+/// number of queries per lib with --benchmark
+const BENCHMARK_RUNS: usize = 3;
+/// maximum line length of the candidates summary
+const SUMMARY_WIDTH: usize = 120;
+const DEFAULT_SHEBANG: &str = "#!/usr/bin/env bash";
+const CONFIG_FILE: &str = "nix-autobahn.toml";
+/// environment variable overriding the ldd executable, e.g. with a stub
+const LDD_ENV: &str = "NIX_AUTOBAHN_LDD";
+const AFTER_HELP: &str = "Defaults for db, strategy, output-format, progress-format, shebang, \
+pkgs, libs and extra-output-types are read from nix-autobahn.toml in the current directory, \
+or else in $XDG_CONFIG_HOME. Options given on the command line take precedence, pkgs, libs and \
+extra-output-types from both are combined.
+
+ENVIRONMENT:
+    NIX_INDEX_DATABASE    directory of the nix-index database
+    NIX_AUTOBAHN_LDD      ldd executable to run instead of the one in $PATH
+
+EXIT CODES:
+    0    success
+    1    any other failure
+    2    the binary was not found
+    3    ldd failed on the binary
+    4    some libraries remain unresolved, the wrapper is written unless --strict
+    5    the nix-index database could not be queried
+    130  cancelled by SIGINT or SIGTERM, no files were written";
+
+/// version of the nix-index crate used to read the database, as locked in
+/// Cargo.lock
+const NIX_INDEX_VERSION: &str = env!("NIX_INDEX_VERSION");
+const APPEND_START: &str = "# nix-autobahn: start";
+const APPEND_END: &str = "# nix-autobahn: end";
+
+/// Set by the SIGINT/SIGTERM handler, checked before each query and before
+/// anything is written
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Bails out with [`Failure::Cancelled`] once a signal was received
+fn check_cancelled() -> anyhow::Result<()> {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Err(Failure::Cancelled.into());
+    }
+    Ok(())
+}
+
+/// Classifies a failed ldd run, which is a cancellation if a signal arrived,
+/// as that kills ldd too
+fn ldd_failure(e: anyhow::Error) -> anyhow::Error {
+    match check_cancelled() {
+        Err(cancelled) => cancelled,
+        Ok(()) => e.context(Failure::Ldd),
+    }
+}
+
+/// Classifies a failed formatter or validator run, which is a cancellation if
+/// a signal arrived, as that kills them too
+fn subprocess_failure(e: anyhow::Error) -> anyhow::Error {
+    check_cancelled().err().unwrap_or(e)
+}
 
-/// Writes a shellscript
-fn write_bash_script(target: &Path, script: &String) -> io::Result<()> {
+/// Writes a shellscript, using `shebang` as its first line
+fn write_bash_script(target: &Path, shebang: &str, script: &str) -> io::Result<()> {
     let mut file = fs::File::create(target)?;
-    file.write_all(format!("#!/usr/bin/env bash\n\n{}", script).as_bytes())?;
+    file.write_all(format!("{}\n\n{}\n", shebang, script).as_bytes())?;
 
     let mut permissions = file.metadata()?.permissions();
     permissions.set_mode(0o755);
@@ -30,229 +129,4779 @@ fn write_bash_script(target: &Path, script: &String) -> io::Result<()> {
     Ok(())
 }
 
-/// Returns the nix expression needed to build an appropiate FHS
-fn fhs_shell<I: Iterator<Item = Package>>(run: &Path, packages: I) -> String {
-    format!(
-        r#"with import <nixpkgs> {{}};
-  buildFHSUserEnv {{
-    name = "fhs";
-    targetPkgs = p: with p; [ 
-      {} 
-    ];
-    runScript = "{}";
-  }}"#,
-        packages
-            .map(|p| p.name)
-            .collect::<Vec<_>>()
-            .join("\n      "),
-        run.to_str().expect("unable to stringify path")
-    )
+/// Returns the shell command building the FHS expression, aborted after
+/// `$NIX_BUILD_TIMEOUT` seconds if a timeout is given
+fn generate_build_command(
+    expression: &str,
+    timeout: Option<u64>,
+    extra_args: &[String],
+    shell: ShellType,
+) -> String {
+    let mut command = String::new();
+    if timeout.is_some() {
+        command += "timeout \"$NIX_BUILD_TIMEOUT\" ";
+    }
+    command += "nix-build --no-out-link";
+    for arg in extra_args {
+        command.push(' ');
+        command += &shell.quote(arg);
+    }
+    command += " -E ";
+    command += &shell.quote(expression);
+    command
 }
 
-/// uses ldd to find missing shared object files on a given binary
-fn missing_libs(binary: &Path) -> anyhow::Result<Vec<MissingLib>> {
-    let output = Command::new("ldd").arg(binary.as_os_str()).output()?;
+/// The shells the wrapper script can be written for
+#[derive(Debug, Default, Clone, Copy, clap::ArgEnum)]
+enum ShellType {
+    #[default]
+    Bash,
+    Fish,
+    Zsh,
+    Sh,
+}
 
-    if !output.status.success() {
-        anyhow::bail!("ldd returned error code {}", output.status);
+impl ShellType {
+    /// Quotes a string for use as a single word in a script of this shell
+    fn quote(self, s: &str) -> String {
+        match self {
+            // fish interprets backslash escapes even within single quotes
+            Self::Fish => format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'")),
+            Self::Bash | Self::Zsh | Self::Sh => shell_quote(s),
+        }
     }
+}
 
-    Ok(String::from_utf8(output.stdout)?
-        .lines()
-        .filter_map(|l| match l.find(LDD_NOT_FOUND) {
-            Some(i) => {
-                let mut s = l.to_string();
-                s.truncate(i);
-                s.remove(0); // get rid of tabulator prefix
-                Some(MissingLib {
-                    name: s.trim().to_string(),
-                })
-            }
-            None => None,
-        })
-        .collect())
+/// Returns the shebang line of a script for the given shell
+fn shebang_for_shell(shell: ShellType) -> &'static str {
+    match shell {
+        ShellType::Bash => DEFAULT_SHEBANG,
+        ShellType::Fish => "#!/usr/bin/env fish",
+        ShellType::Zsh => "#!/usr/bin/env zsh",
+        ShellType::Sh => "#!/bin/sh",
+    }
 }
 
-/// A missing library, identified by the filename (without preceding dirnames)
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct MissingLib {
-    name: String,
+/// Knobs for the generated wrapper scripts
+#[derive(Debug, Default)]
+struct ScriptOptions {
+    /// first line of the script
+    shebang: String,
+
+    /// shell the script is written for
+    shell: ShellType,
+
+    /// seconds after which building the FHS is aborted
+    nix_build_timeout: Option<u64>,
+
+    /// additional arguments to the nix build
+    nix_build_args: Vec<String>,
 }
 
-impl MissingLib {
-    /// uses nix-locate to find candidate packages providing a given file,
-    /// identified by a file name
-    fn find_candidates(&self) -> anyhow::Result<Vec<Package>> {
-        let db_path = dirs::home_dir()
-            .ok_or_else(|| anyhow::format_err!("unable to find home dir"))?
-            .join(".cache/nix-index/");
-        let db = nix_index::database::Reader::open(db_path)
-            .map_err(|_| anyhow::format_err!("oh no, a nix-index error"))?;
-        let regex = Regex::new(&self.name)?;
-        let query = db.query(&regex);
-        query
-            .run()
-            .unwrap()
-            .map(|x| {
-                x.map(|p| Package {
-                    name: format!("{}.{}", p.0.origin().attr, p.0.origin().output),
-                })
-                .map_err(|_| anyhow::format_err!("oh no, a nix-index error"))
-            })
-            .collect()
+impl ScriptOptions {
+    /// The assignment of `NIX_BUILD_TIMEOUT`, if a timeout is given
+    fn timeout_variable(&self) -> String {
+        let secs = match self.nix_build_timeout {
+            Some(secs) => secs,
+            None => return String::new(),
+        };
+        let assignment = match self.shell {
+            ShellType::Fish => format!("set NIX_BUILD_TIMEOUT {}", secs),
+            _ => format!("NIX_BUILD_TIMEOUT={}", secs),
+        };
+        format!(
+            "# seconds after which building the FHS is aborted, failing the script\n{}\n",
+            assignment
+        )
+    }
+
+    /// The script's arguments, to be passed on
+    fn args(&self) -> &'static str {
+        match self.shell {
+            ShellType::Fish => "$argv",
+            _ => "\"$@\"",
+        }
+    }
+
+    /// Builds the FHS with nix-build and execs it, so that signals reach the
+    /// binary directly. A failed build fails the script, rather than leaving
+    /// the arguments to be run on their own.
+    fn exec_fhs(&self, expression: &str) -> String {
+        let build = generate_build_command(
+            expression,
+            self.nix_build_timeout,
+            &self.nix_build_args,
+            self.shell,
+        );
+        match self.shell {
+            // a failed command substitution expands to nothing in fish
+            ShellType::Fish => format!(
+                "set fhs ({}); or exit 1; exec $fhs/bin/fhs {}",
+                build,
+                self.args()
+            ),
+            // an empty substitution would leave `exec /bin/fhs`
+            _ => format!(
+                "fhs=$({}) || exit; exec \"$fhs/bin/fhs\" {}",
+                build,
+                self.args()
+            ),
+        }
     }
 }
 
-/// A package providing a lib
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Package {
-    name: String,
+/// Writes a shellscript running the FHS through nix-portable, falling back to
+/// a regular nix-build if nix-portable is not available at runtime. The store
+/// of nix-portable is virtual, so the FHS can only run within nix-portable.
+fn write_nix_portable_script(
+    target: &Path,
+    expression: &str,
+    nix_portable: Option<&Path>,
+    options: &ScriptOptions,
+) -> io::Result<()> {
+    let shell = options.shell;
+    let nix_portable = shell.quote(
+        nix_portable
+            .map(|p| p.to_str().expect("unable to stringify path"))
+            .unwrap_or("nix-portable"),
+    );
+    let mut flags = String::from(" --impure");
+    for arg in &options.nix_build_args {
+        flags.push(' ');
+        flags += &shell.quote(arg);
+    }
+    flags += " --expr ";
+    flags += &shell.quote(expression);
+    let fish = matches!(shell, ShellType::Fish);
+    // the timeout must only cover the build, not the binary
+    let build = match options.nix_build_timeout {
+        Some(_) => format!(
+            "  timeout \"$NIX_BUILD_TIMEOUT\" {} nix build --no-link{}{}\n",
+            nix_portable,
+            flags,
+            if fish { "; or exit" } else { " || exit" }
+        ),
+        None => String::new(),
+    };
+    let run = format!(
+        "  exec {} nix run{} -- {}",
+        nix_portable,
+        flags,
+        options.args()
+    );
+    let fallback = options.exec_fhs(expression);
+    let script = if fish {
+        format!(
+            "if command -v {} > /dev/null\n{}{}\nelse\n  {}\nend",
+            nix_portable, build, run, fallback
+        )
+    } else {
+        format!(
+            "if command -v {} > /dev/null; then\n{}{}\nelse\n  {}\nfi",
+            nix_portable, build, run, fallback
+        )
+    };
+    write_bash_script(
+        target,
+        &options.shebang,
+        &format!("{}{}", options.timeout_variable(), script),
+    )
 }
 
-#[derive(Parser)]
-#[clap(version, author, about)]
-struct Opts {
-    /// dynamically linked binary to be examined
-    binary: PathBuf,
+/// Writes a shellscript running the binary through steam-run, whose generic FHS
+/// runs many binaries without resolving anything, at the cost of a large
+/// closure. steam-run is taken from nixpkgs if it is not installed.
+fn write_steam_run_script(target: &Path, shebang: &str, binary: &Path) -> io::Result<()> {
+    let binary = shell_quote(binary.to_str().expect("unable to stringify path"));
+    write_bash_script(
+        target,
+        shebang,
+        &format!(
+            r#"if command -v steam-run > /dev/null; then
+  exec steam-run {binary} "$@"
+else
+  # steam-run is unfree
+  exec "$(NIXPKGS_ALLOW_UNFREE=1 nix-build --no-out-link '<nixpkgs>' -A steam-run)/bin/steam-run" {binary} "$@"
+fi"#
+        ),
+    )
+}
 
-    /// additional shared object files to search for and propagate
-    #[clap(short, long = "lib")]
-    libs: Vec<String>,
+/// Writes a nix expression bundling the FHS with nix-bundle, which must be
+/// available in nixpkgs. Building it yields a single self-extracting executable
+/// carrying the closure of all packages, which thus runs without a nix store
+/// on the host. The binary itself is not part of the closure, it is expected
+/// at the same path.
+fn write_nix_bundle_expression(target: &Path, fhs_expression: &str) -> io::Result<()> {
+    fs::write(
+        target,
+        format!(
+            r#"with import <nixpkgs> {{}};
+let
+  fhs = {};
+  nix-bundle-lib = import "${{nix-bundle}}/share/nix-bundle/default.nix" {{ nixpkgs = pkgs; }};
+in
+nix-bundle-lib.nix-bootstrap {{
+  target = fhs;
+  run = "/bin/fhs";
+}}
+"#,
+            fhs_expression
+        ),
+    )
+}
 
-    /// additional packages to propagate
-    #[clap(short, long = "pkg")]
-    pkgs: Vec<String>,
+/// Writes a nix expression for a package, whose `bin/<binary-name>` launches the
+/// binary in the FHS, so that it can be installed like any other package
+fn write_derivation_nix(target: &Path, binary: &Path, fhs_expression: &str) -> io::Result<()> {
+    let name = binary
+        .file_name()
+        .expect("a canonical file path has a file name")
+        .to_str()
+        .expect("unable to stringify path");
+    fs::write(
+        target,
+        format!(
+            r#"with import <nixpkgs> {{}};
+let
+  fhs = {fhs_expression};
+in
+stdenv.mkDerivation rec {{
+  name = "{name}";
+  dontUnpack = true;
+  installPhase = ''
+    mkdir -p $out/bin
+    ln -s ${{fhs}}/bin/fhs "$out/bin/${{name}}"
+  '';
+}}
+"#,
+            name = nix_string_escape(name),
+        ),
+    )
+}
 
-    #[clap(long)]
-    print_found_packages: bool,
+/// Writes a nix expression copying the binary to the store and wrapping it with
+/// an `LD_LIBRARY_PATH` of the packages, instead of building an FHS. Building it
+/// yields `result/bin/<binary-name>`, which is lighter than an FHS but does not
+/// help binaries expecting resources at hardcoded paths.
+fn write_wrap_program_nix<'a>(
+    target: &Path,
+    binary: &Path,
+    packages: impl Iterator<Item = &'a Package>,
+) -> io::Result<()> {
+    let name = binary
+        .file_name()
+        .expect("a canonical file path has a file name")
+        .to_str()
+        .expect("unable to stringify path");
+    let expression = format!(
+        r#"# best-effort wrapping of a binary not built by nix, only its libraries are
+# provided through LD_LIBRARY_PATH, hardcoded paths are not taken care of
+with import <nixpkgs> {{}};
+stdenv.mkDerivation {{
+  name = "{drv_name}-wrapped";
+  src = /. + "{src}";
+  dontUnpack = true;
+  nativeBuildInputs = [ makeWrapper ];
+  installPhase = ''
+    install -Dm755 $src $out/libexec/{file}
+    makeWrapper $out/libexec/{file} $out/bin/{file} \
+      --set LD_LIBRARY_PATH "${{lib.makeLibraryPath [
+        {packages}
+      ]}}"
+  '';
+}}
+"#,
+        // derivation names are restricted to a few characters
+        drv_name = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '+' | '-' | '.' | '_' | '?' | '=' => c,
+                _ => '-',
+            })
+            .collect::<String>(),
+        file = nix_indented_string_escape(&shell_quote(name)),
+        src = nix_string_escape(binary.to_str().expect("unable to stringify path")),
+        packages = packages
+            .map(|p| p.to_nix_attr())
+            .collect::<Vec<_>>()
+            .join("\n        "),
+    );
+    fs::write(target, expression)
+}
 
-    #[clap(arg_enum, short, long, default_value_t)]
-    output_format: Output,
+/// Quotes a string for use as a single word in a shell script
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
 
-    #[clap(arg_enum, short, long, default_value_t)]
-    strategy: Strategy,
+/// Escapes a string for use inside of a double quoted nix string
+fn nix_string_escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace("${", r"\${")
 }
 
-#[derive(Clone, clap::ArgEnum)]
-enum Output {
-    NixShell,
+/// Escapes a string for use inside of an indented (`''`) nix string
+fn nix_indented_string_escape(s: &str) -> String {
+    s.replace("''", "'''").replace("${", "''${")
 }
 
-impl Default for Output {
-    fn default() -> Self {
-        Self::NixShell
-    }
+/// Writes a shell.nix providing the packages' libraries along with a
+/// `run-binary` command launching the binary, as in
+/// `nix-shell --run 'run-binary <args>'`
+fn write_nix_shell_compat<'a>(
+    target: &Path,
+    binary: &Path,
+    packages: impl Iterator<Item = &'a Package>,
+) -> io::Result<()> {
+    let packages = packages.map(|p| p.to_nix_attr()).collect::<Vec<_>>();
+    let run = shell_quote(binary.to_str().expect("unable to stringify path"));
+    fs::write(
+        target,
+        format!(
+            r#"with import <nixpkgs> {{}};
+mkShell {{
+  packages = [
+    {packages}
+    (writeShellScriptBin "run-binary" ''
+      exec {run} "$@"
+    '')
+  ];
+  shellHook = ''
+    export LD_LIBRARY_PATH=${{lib.makeLibraryPath [ {libs} ]}}
+  '';
+}}
+"#,
+            packages = packages.join("\n    "),
+            libs = packages.join(" "),
+            run = nix_indented_string_escape(&run),
+        ),
+    )
 }
 
-#[derive(Clone, clap::ArgEnum)]
-enum Strategy {
-    TakeAll,
+/// Knobs for the generated FHS expression, beside the packages
+#[derive(Debug, Default)]
+struct FhsOptions {
+    /// directories visible read-only inside the FHS
+    binds: Vec<PathBuf>,
+
+    /// directories visible and writable inside the FHS
+    rw_binds: Vec<PathBuf>,
+
+    /// environment variables exported before the binary is launched
+    env: Vec<(String, String)>,
+
+    /// shell snippets run upon entering the FHS, after the exports
+    profile_snippets: Vec<String>,
+
+    /// skip the default `/etc/profile` of the FHS
+    no_profile: bool,
+
+    /// skip the default `/etc` of the FHS
+    no_etc: bool,
+
+    /// shell commands run in the FHS root after it is built
+    extra_build_commands: Option<String>,
+
+    /// the nixpkgs function building the FHS
+    backend: FhsBackend,
+
+    /// the platform nixpkgs is imported for, the host's if unset
+    system: Option<String>,
+
+    /// overlay files nixpkgs is imported with, as absolute paths
+    overlays: Vec<PathBuf>,
+
+    /// comments rendered after packages in `targetPkgs`, e.g. the libs they
+    /// were selected for
+    annotations: HashMap<Package, String>,
+
+    /// the pkgsCross attribute `targetPkgs` are taken from, if any
+    pkgs_cross: Option<String>,
+
+    /// packages providing static libraries, only mentioned in a comment
+    static_packages: Vec<Package>,
+
+    /// directory the binary is launched in
+    chdir: Option<PathBuf>,
+
+    /// the name the binary is launched with as `argv[0]`
+    argv0: Option<String>,
+
+    /// shell command launched instead of the binary, which receives the
+    /// arguments
+    run_script: Option<String>,
+
+    /// render `targetPkgs` in the given order, rather than sorted by attribute
+    keep_order: bool,
+}
+
+/// The ways nixpkgs offers to build an FHS
+#[derive(Debug, Default, Clone, Copy, clap::ArgEnum)]
+enum FhsBackend {
+    /// whatever nixpkgs builds by default, bubblewrap since 23.05 and a chroot
+    /// before
+    #[default]
+    UserEnv,
+    /// a chroot, which exposes all of the host's directories
+    Chroot,
+    /// a bubblewrap sandbox, which works rootless and exposes only the binds
+    Bubblewrap,
 }
 
-impl Default for Strategy {
-    fn default() -> Self {
-        Self::TakeAll
+impl FhsBackend {
+    fn builder(self) -> &'static str {
+        match self {
+            Self::UserEnv => "buildFHSUserEnv",
+            Self::Chroot => "buildFHSEnvChroot",
+            Self::Bubblewrap => "buildFHSEnvBubblewrap",
+        }
     }
 }
 
-fn new_spinner(msg: &'static str) -> ProgressBar {
-    let style = ProgressStyle::default_spinner().on_finish(ProgressFinish::AndLeave);
-    ProgressBar::new_spinner()
-        .with_style(style)
-        .with_message(msg)
+/// Returns the nix expression needed to build an appropiate FHS, running `run`
+/// or else just bash
+fn fhs_shell<'a, I: Iterator<Item = &'a Package>>(
+    run: Option<&Path>,
+    packages: I,
+    options: &FhsOptions,
+) -> String {
+    render_fhs(options.backend.builder(), run, packages, options)
 }
 
-fn new_progress(count: u64, msg: &'static str) -> ProgressBar {
-    let style = ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-        .progress_chars("##-")
-        .on_finish(ProgressFinish::AndLeave);
-    ProgressBar::new(count).with_style(style).with_message(msg)
+/// Returns the nix expression needed to build an appropiate FHS for a foreign
+/// architecture, `target` being an attribute of `pkgsCross`
+fn fhs_shell_cross<'a, I: Iterator<Item = &'a Package>>(
+    target: &str,
+    run: Option<&Path>,
+    packages: I,
+    options: &FhsOptions,
+) -> String {
+    render_fhs(
+        &format!("pkgsCross.{}.{}", target, options.backend.builder()),
+        run,
+        packages,
+        options,
+    )
+}
+
+/// Renders the FHS expression using the given builder function
+fn render_fhs<'a, I: Iterator<Item = &'a Package>>(
+    builder: &str,
+    run: Option<&Path>,
+    packages: I,
+    options: &FhsOptions,
+) -> String {
+    let mut packages: Vec<_> = packages.collect();
+    // sorted, so that regenerating the expression yields no spurious diffs
+    if !options.keep_order {
+        packages.sort_by_key(|p| p.to_nix_attr());
+    }
+    packages.dedup_by_key(|p| p.to_nix_attr());
+    let packages: Vec<_> = packages
+        .into_iter()
+        .map(|p| match options.annotations.get(p) {
+            Some(comment) => format!("{} # {}", p.to_nix_attr(), comment),
+            None => p.to_nix_attr(),
+        })
+        .collect();
+    format!(
+        r#"{}with import <nixpkgs> {{{}}};
+  {} {{
+    name = "fhs";
+    targetPkgs = p: with p{}; {};{}{}
+    profile = "{}";
+    extraBuildCommands = "{}";
+    runScript = {};
+  }}"#,
+        match run {
+            Some(_) => "",
+            None => "# a bare FHS, its bin/fhs opens a bash, as does its env with nix-shell\n",
+        },
+        {
+            let mut args = String::new();
+            if let Some(system) = &options.system {
+                args += &format!(" system = \"{}\";", nix_string_escape(system));
+            }
+            if !options.overlays.is_empty() {
+                let overlays: Vec<_> = options
+                    .overlays
+                    .iter()
+                    .map(|o| {
+                        let o = nix_string_escape(o.to_str().expect("unable to stringify path"));
+                        format!(r#"(import (/. + "{}"))"#, o)
+                    })
+                    .collect();
+                args += &format!(" overlays = [ {} ];", overlays.join(" "));
+            }
+            if !args.is_empty() {
+                args.push(' ');
+            }
+            args
+        },
+        builder,
+        match &options.pkgs_cross {
+            Some(target) => format!(".pkgsCross.{}", target),
+            None => String::new(),
+        },
+        if packages.is_empty() {
+            "[ ]".to_string()
+        } else {
+            format!("[\n      {}\n    ]", packages.join("\n      "))
+        },
+        if options.static_packages.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "\n    # staticPkgs, providing static libraries to link against:\n    #   {}",
+                options
+                    .static_packages
+                    .iter()
+                    .map(Package::to_nix_attr)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        },
+        match options.backend {
+            // the chroot sees all of the host's directories anyways
+            FhsBackend::Chroot => String::new(),
+            // the chroot flavour of buildFHSUserEnv ignores the bwrap arguments
+            FhsBackend::UserEnv | FhsBackend::Bubblewrap => format!(
+                "\n    extraBwrapArgs = [\n      {}\n    ];",
+                // read-write binds come last, so they win over read-only ones
+                options
+                    .binds
+                    .iter()
+                    .map(|b| ("--ro-bind", b))
+                    .chain(options.rw_binds.iter().map(|b| ("--bind", b)))
+                    .map(|(arg, b)| {
+                        let b = nix_string_escape(&b.to_string_lossy());
+                        format!(r#""{}" "{1}" "{1}""#, arg, b)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n      ")
+            ),
+        },
+        nix_string_escape(
+            &options
+                .env
+                .iter()
+                .map(|(k, v)| format!("export {}={}\n", k, shell_quote(v)))
+                .chain(options.profile_snippets.iter().cloned())
+                .collect::<String>()
+        ),
+        nix_string_escape(
+            &[
+                (options.no_etc, "rm -rf etc\n"),
+                (options.no_profile && !options.no_etc, "rm -f etc/profile\n"),
+            ]
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, command)| *command)
+            .chain(options.extra_build_commands.as_deref())
+            .collect::<String>()
+        ),
+        {
+            let run = run.map(|r| r.to_str().expect("unable to stringify path"));
+            // only the binary itself is launched with a custom argv[0]
+            let argv0 = options
+                .argv0
+                .as_ref()
+                .filter(|_| run.is_some() && options.run_script.is_none());
+            match (&options.chdir, argv0, &options.run_script) {
+                (None, None, Some(script)) => format!("\"{}\"", nix_string_escape(script)),
+                (None, None, None) => format!("\"{}\"", nix_string_escape(run.unwrap_or("bash"))),
+                (chdir, argv0, script) => {
+                    let cd = chdir.as_ref().map_or_else(String::new, |dir| {
+                        format!(
+                            "cd {} && ",
+                            shell_quote(dir.to_str().expect("unable to stringify path"))
+                        )
+                    });
+                    let exec = argv0.map_or_else(
+                        || "exec".to_string(),
+                        |argv0| format!("exec -a {}", shell_quote(argv0)),
+                    );
+                    let command = script
+                        .clone()
+                        .unwrap_or_else(|| run.map_or_else(|| "bash".to_string(), shell_quote));
+                    format!(
+                        "writeShellScript \"run\" ''\n      {}\n    ''",
+                        nix_indented_string_escape(&format!("{}{} {} \"$@\"", cd, exec, command))
+                    )
+                }
+            }
+        }
+    )
 }
 
-fn main() -> anyhow::Result<()> {
-    let mut opts: Opts = Opts::parse();
+/// Parses a `KEY=VALUE` pair for an environment variable
+fn parse_env_var(s: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::format_err!("expected KEY=VALUE, got `{}`", s))?;
+    let valid_key = !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_key {
+        anyhow::bail!("`{}` is not a valid environment variable name", key);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
 
-    // initilizes packages list and adds additional-packages right away, if
-    // provided
+/// Adds packages to an existing nix file, rewriting only the region between the
+/// `# nix-autobahn: start` and `# nix-autobahn: end` sentinel comments
+fn inject_packages(file: &Path, packages: &[Package]) -> anyhow::Result<()> {
+    let content = fs::read_to_string(file)?;
+    let start = content
+        .find(APPEND_START)
+        .ok_or_else(|| anyhow::format_err!("{} lacks `{}`", file.display(), APPEND_START))?
+        + APPEND_START.len();
+    let end = content[start..]
+        .find(APPEND_END)
+        .ok_or_else(|| anyhow::format_err!("{} lacks `{}`", file.display(), APPEND_END))?
+        + start;
 
-    opts.pkgs.dedup();
+    // reuse the indentation of the start sentinel's line for all package lines
+    let line = content[..start - APPEND_START.len()]
+        .rsplit('\n')
+        .next()
+        .unwrap_or("");
+    let indent = &line[..line.len() - line.trim_start().len()];
 
-    let mut packages_included: Vec<_> = opts
-        .pkgs
-        .into_iter()
-        .map(|name| Arc::new(Package { name }))
+    let mut lines: Vec<String> = content[start..end]
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
         .collect();
+    for p in packages {
+        let name = p.to_nix_attr();
+        if !lines
+            .iter()
+            .any(|l| l.split_whitespace().next() == Some(name.as_str()))
+        {
+            lines.push(name);
+        }
+    }
 
-    let pb = new_spinner("scanning for missing libs");
+    let region: String = lines.iter().map(|l| format!("\n{}{}", indent, l)).collect();
+    fs::write(
+        file,
+        format!(
+            "{}{}\n{}{}",
+            &content[..start],
+            region,
+            indent,
+            &content[end..]
+        ),
+    )?;
 
-    let mut missing_libs: Vec<_> = opts
-        .libs
+    Ok(())
+}
+
+/// Returns the package itself along with the given other outputs of the same
+/// attribute, e.g. `zlib.out` with `["dev"]` yields `zlib.out` and `zlib.dev`
+fn expand_package_outputs(pkg: &Package, extra_outputs: &[String]) -> Vec<Package> {
+    std::iter::once(pkg.clone())
+        .chain(extra_outputs.iter().map(|output| Package {
+            attr: pkg.attr.clone(),
+            output: output.clone(),
+        }))
+        .collect()
+}
+
+/// Removes duplicate packages, keeping the first occurrence. Attributes naming
+/// an output, e.g. `zlib.dev`, count as that output of the package, so that
+/// `zlib` and `zlib.out` are duplicates as well.
+fn dedup_packages(packages: Vec<Arc<Package>>) -> Vec<Arc<Package>> {
+    let mut seen = std::collections::HashSet::new();
+    packages
         .into_iter()
-        .progress_with(pb)
-        .map(|name| MissingLib { name })
-        .chain(missing_libs(&opts.binary)?.into_iter())
-        .collect();
+        .map(|p| match p.output.as_str() {
+            "out" => Arc::new(Package::from_attr(p.attr.clone())),
+            _ => p,
+        })
+        .filter(|p| seen.insert(p.clone()))
+        .collect()
+}
 
-    let pb = new_spinner("refining missing libs");
+/// Shortens a soname to at most one version component, e.g. `libssl.so.1.1.1l`
+/// becomes `libssl.so.1`
+fn strip_soname_version(soname: &str) -> String {
+    match soname.find(".so.") {
+        Some(i) => {
+            let (base, versions) = soname.split_at(i + ".so.".len());
+            format!("{}{}", base, versions.split('.').next().unwrap_or(""))
+        }
+        None => soname.to_string(),
+    }
+}
 
-    packages_included.sort();
-    missing_libs.sort();
-    missing_libs.dedup();
-    pb.finish();
+/// Returns the directory of the nix-index database, as given by
+/// `$NIX_INDEX_DATABASE`, or else in the XDG cache dir, falling back to
+/// `~/.cache/nix-index/`
+fn default_db_path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = std::env::var_os("NIX_INDEX_DATABASE") {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(path) = dirs::cache_dir().map(|d| d.join("nix-index")) {
+        if path.is_dir() {
+            return Ok(path);
+        }
+    }
+    Ok(dirs::home_dir()
+        .ok_or_else(|| anyhow::format_err!("unable to find home dir"))?
+        .join(".cache/nix-index/"))
+}
 
-    let pb = new_progress(missing_libs.len() as u64, "loooking up candidate packages");
+/// Returns the nix-index database to query, as chosen by `--db` and `--cross`
+fn db_path(db: Option<&Path>, cross_target: Option<&str>) -> anyhow::Result<PathBuf> {
+    match (db, cross_target) {
+        (Some(db), _) => Ok(db.to_path_buf()),
+        (None, Some(target)) => Ok(default_db_path()?.join(target)),
+        (None, None) => default_db_path(),
+    }
+}
 
-    let missing_map: HashMap<Arc<MissingLib>, Vec<Arc<Package>>> = missing_libs
-        .par_iter()
-        .progress_with(pb)
+/// Runs a command, returning the first line of its output if it succeeded
+fn command_output(program: &str, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::format_err!("unable to run {}: {}", program, e))?;
+    if !output.status.success() {
+        anyhow::bail!("{} returned error code {}", program, output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+/// Evaluates the store path of a package output in nixpkgs, without building it
+fn resolve_store_path(pkg: &Package) -> anyhow::Result<PathBuf> {
+    let expr = format!("(import <nixpkgs> {{}}).{}.outPath", pkg);
+    let output = command_output("nix-instantiate", &["--eval", "--strict", "-E", &expr])?;
+    let path = output
+        .strip_prefix('"')
+        .and_then(|o| o.strip_suffix('"'))
+        .ok_or_else(|| anyhow::format_err!("unexpected output of nix-instantiate: {}", output))?;
+    Ok(PathBuf::from(path))
+}
+
+/// Runs a command with `input` on its stdin, returning its stdout
+fn pipe_through(program: &str, args: &[&str], input: &str) -> anyhow::Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::format_err!("unable to run {}: {}", program, e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} returned error code {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// The first available nix formatter along with the arguments making it format
+/// stdin to stdout
+fn find_nix_formatter() -> Option<(&'static str, &'static [&'static str])> {
+    const FORMATTERS: [(&str, &[&str]); 2] =
+        [("nixpkgs-fmt", &[]), ("alejandra", &["--quiet", "-"])];
+    FORMATTERS
+        .iter()
+        .copied()
+        .find(|(program, _)| command_output(program, &["--version"]).is_ok())
+}
+
+/// Formats a nix expression with nixpkgs-fmt or else alejandra, leaving it as
+/// is if neither is available
+fn format_nix_expression(expr: &str) -> anyhow::Result<String> {
+    match find_nix_formatter() {
+        Some((program, args)) => pipe_through(program, args, expr),
+        None => Ok(expr.to_string()),
+    }
+}
+
+/// Checks that a nix expression parses, through `nix-instantiate --parse`
+fn validate_nix_expression(expr: &str) -> anyhow::Result<()> {
+    pipe_through("nix-instantiate", &["--parse", "-"], expr)
+        .map(|_| ())
+        .with_context(|| format!("the generated expression is invalid:\n{}", expr))
+}
+
+/// Checks that the nix-index database is readable, returning its path and age
+fn check_db(db: Option<&Path>, cross_target: Option<&str>) -> anyhow::Result<String> {
+    let db_path = db_path(db, cross_target)?;
+    let db_file = db_path.join("files");
+    fs::File::open(&db_file)
+        .map_err(|e| anyhow::format_err!("unable to read {}: {}", db_file.display(), e))?;
+    Ok(format!(
+        "{} ({:.0} days old)",
+        db_file.display(),
+        db_age_days(&db_path)?.floor()
+    ))
+}
+
+/// Returns the days since the nix-index database was last modified, as told
+/// by its `files`, or the directory itself if there is none
+fn db_age_days(db_path: &Path) -> anyhow::Result<f64> {
+    let db_file = db_path.join("files");
+    let metadata = fs::metadata(&db_file)
+        .or_else(|_| fs::metadata(db_path))
+        .with_context(|| format!("unable to stat {}", db_path.display()))?;
+    let age = metadata.modified()?.elapsed().unwrap_or_default();
+    Ok(age.as_secs_f64() / (60.0 * 60.0 * 24.0))
+}
+
+/// Returns the version of nix-autobahn together with the state of the nix-index
+/// database, as useful for bug reports
+fn build_version_string(db: Option<&Path>, cross_target: Option<&str>) -> String {
+    format!(
+        "{}\nnix-index crate: {}\ndatabase: {}",
+        env!("CARGO_PKG_VERSION"),
+        NIX_INDEX_VERSION,
+        check_db(db, cross_target).unwrap_or_else(|e| format!("unavailable ({})", e))
+    )
+}
+
+/// Sums up the sizes of all store paths in the joint closure of the packages,
+/// using a single nix invocation for all of them
+fn closure_size(packages: &[Package]) -> anyhow::Result<u64> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--recursive",
+            "--size",
+            "--file",
+            "<nixpkgs>",
+        ])
+        .args(packages.iter().map(|p| p.to_string()))
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix path-info returned error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout)?
+        .lines()
         .map(|l| {
-            (
-                Arc::new(l.clone()),
-                l.find_candidates()
-                    .unwrap()
-                    .into_iter()
-                    .map(Arc::new)
-                    .collect(),
-            )
+            l.split_whitespace()
+                .nth(1)
+                .ok_or_else(|| anyhow::format_err!("unexpected nix path-info line `{}`", l))?
+                .parse::<u64>()
+                .map_err(Into::into)
         })
+        .sum()
+}
+
+/// Formats a number of bytes with a binary prefix
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Prints the values of an argument enum along with their descriptions
+fn print_variants<E: clap::ArgEnum>() {
+    let values: Vec<_> = E::value_variants()
+        .iter()
+        .filter_map(E::to_possible_value)
         .collect();
+    let width = values.iter().map(|v| v.get_name().len()).max().unwrap_or(0);
+    for value in values {
+        println!(
+            "{:width$}  {}",
+            value.get_name(),
+            value.get_help().unwrap_or_default(),
+            width = width
+        );
+    }
+}
 
-    let candidates_map: HashMap<Arc<Package>, Vec<Arc<MissingLib>>> =
-        missing_map
-            .iter()
-            .fold(HashMap::new(), |mut accum, (l, ps)| {
-                ps.iter()
-                    .for_each(|p| accum.entry(p.clone()).or_insert(Vec::new()).push(l.clone()));
-                accum
-            });
+/// Formats events for the terminal as plain messages, marking warnings and
+/// errors like the rest of the output does
+struct StderrFormat;
 
-    // TODO please find a good selection
-    // this is the full set
-    packages_included.extend(candidates_map.keys().cloned());
+impl<S, N> tracing_subscriber::fmt::FormatEvent<S, N> for StderrFormat
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        match *event.metadata().level() {
+            tracing::Level::ERROR => write!(writer, "error: ")?,
+            tracing::Level::WARN => write!(writer, "warning: ")?,
+            _ => {}
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
 
-    if opts.print_found_packages {
-        println!(
-            "[ {} ]",
-            packages_included
-                .iter()
-                .map(|p| p.name.clone())
-                .collect::<Vec<_>>()
-                .join(" ")
+/// Logs events up to `log_level` to stderr and, given a log file, all events
+/// down to debug to the file, each line prefixed by a timestamp. The file is
+/// appended to, so it collects the logs of consecutive runs.
+fn setup_logging(log_level: LevelFilter, log_file: Option<&Path>) -> anyhow::Result<()> {
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+    let file_layer = match log_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("unable to open {}", path.display()))?;
+            Some(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(std::sync::Mutex::new(file))
+                    .with_filter(LevelFilter::DEBUG),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(file_layer)
+        .with(
+            fmt::layer()
+                .event_format(StderrFormat)
+                .with_writer(io::stderr)
+                .with_filter(log_level),
         )
-    }
+        .try_init()?;
+    Ok(())
+}
 
-    // build FHS expression
-    let fhs_expression = fhs_shell(
-        &opts.binary.canonicalize()?,
-        packages_included.iter().map(|p| p.as_ref().clone()),
-    );
-    // write bash script with the FHS expression
-    write_bash_script(
-        &opts.binary.with_file_name("run-with-nix"),
-        &format!("$({NIX_BUILD_FHS} '{fhs_expression}')/bin/fhs"),
-    )
-    .unwrap();
+/// Checks the environment nix-autobahn depends on and prints a report
+fn doctor(db: Option<&Path>, cross_target: Option<&str>) -> anyhow::Result<()> {
+    let checks = [
+        ("ldd", command_output("ldd", &["--version"])),
+        ("nix-index database", check_db(db, cross_target)),
+        ("nix-build", command_output("nix-build", &["--version"])),
+        (
+            "<nixpkgs>",
+            command_output("nix-instantiate", &["--find-file", "nixpkgs"]),
+        ),
+    ];
+
+    let mut failed = 0;
+    for (name, result) in &checks {
+        match result {
+            Ok(detail) => println!("{} {}: {}", console::style("pass").green(), name, detail),
+            Err(e) => {
+                failed += 1;
+                println!("{} {}: {}", console::style("fail").red(), name, e);
+            }
+        }
+    }
 
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
     Ok(())
 }
+
+/// Reads the non-blank lines of a file, skipping `#` comments. A leading `~`
+/// in the path is expanded to the home directory.
+fn read_list_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let path = PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).as_ref());
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("unable to read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Reads additional libs from a file, one soname per line
+fn read_libs_file(path: &Path) -> anyhow::Result<Vec<MissingLib>> {
+    Ok(read_list_file(path)?
+        .into_iter()
+        .map(|name| MissingLib { name })
+        .collect())
+}
+
+/// Reads additional packages from a file, one attribute per line
+fn read_pkgs_file(path: &Path) -> anyhow::Result<Vec<Package>> {
+    Ok(read_list_file(path)?
+        .into_iter()
+        .map(Package::from_attr)
+        .collect())
+}
+
+/// The packages providing the common sound libs
+fn audio_packages() -> Vec<Package> {
+    ["pulseaudio", "alsa-lib", "pipewire"]
+        .iter()
+        .map(|attr| Package::from_attr(attr.to_string()))
+        .collect()
+}
+
+/// The packages needed for the binary to find fonts
+fn fontconfig_packages() -> Vec<Package> {
+    ["fontconfig", "freetype", "xorg.libXft", "noto-fonts"]
+        .iter()
+        .map(|attr| Package::from_attr(attr.to_string()))
+        .collect()
+}
+
+/// The profile snippet making fontconfig pick up the fonts of the FHS
+fn fontconfig_profile_script() -> &'static str {
+    "fc-cache -f > /dev/null 2>&1\n"
+}
+
+/// The candidates of each lib as recorded in a lockfile, along with the
+/// database they were looked up in
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Lockfile {
+    db_fingerprint: String,
+
+    /// the `[attr, output]` pairs of each lib's candidates. Plain arrays keep
+    /// every entry a TOML value, so that empty and non-empty ones mix.
+    candidates: std::collections::BTreeMap<String, Vec<(String, String)>>,
+}
+
+impl Lockfile {
+    fn new(
+        db_fingerprint: String,
+        missing_map: &HashMap<Arc<MissingLib>, Vec<Arc<Package>>>,
+    ) -> Self {
+        let candidates = missing_map
+            .iter()
+            .map(|(l, ps)| {
+                let ps = ps
+                    .iter()
+                    .map(|p| (p.attr.clone(), p.output.clone()))
+                    .collect();
+                (l.name.clone(), ps)
+            })
+            .collect();
+        Self {
+            db_fingerprint,
+            candidates,
+        }
+    }
+
+    fn into_candidates(self) -> HashMap<MissingLib, Vec<Package>> {
+        self.candidates
+            .into_iter()
+            .map(|(name, ps)| {
+                let ps = ps
+                    .into_iter()
+                    .map(|(attr, output)| Package { attr, output })
+                    .collect();
+                (MissingLib { name }, ps)
+            })
+            .collect()
+    }
+}
+
+/// Identifies the state of the nix-index database by its size and
+/// modification time
+fn db_fingerprint(db: &Database) -> anyhow::Result<String> {
+    let db_file = db.path.join("files");
+    let metadata =
+        fs::metadata(&db_file).with_context(|| format!("unable to stat {}", db_file.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!("{}-{}", metadata.len(), modified.as_secs()))
+}
+
+/// Reads the candidates recorded in a lockfile, which are only valid if the
+/// database did not change since. A missing lockfile records nothing, neither
+/// does any without a database to check it against.
+fn read_lockfile(path: &Path, db: &Database) -> anyhow::Result<HashMap<MissingLib, Vec<Package>>> {
+    let fingerprint = match db_fingerprint(db) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            tracing::warn!("ignoring the lockfile: {:#}", e);
+            return Ok(HashMap::new());
+        }
+    };
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("unable to read {}", path.display())),
+    };
+    tracing::debug!("reading the lockfile {}", path.display());
+    let lockfile: Lockfile =
+        toml::from_str(&content).with_context(|| format!("unable to parse {}", path.display()))?;
+    if lockfile.db_fingerprint != fingerprint {
+        tracing::warn!(
+            "the nix-index database changed since the lockfile was written, ignoring it"
+        );
+        return Ok(HashMap::new());
+    }
+    Ok(lockfile.into_candidates())
+}
+
+/// Records the candidates of each lib in a lockfile, which is skipped without
+/// a database to fingerprint
+fn write_lockfile(
+    path: &Path,
+    db: &Database,
+    missing_map: &HashMap<Arc<MissingLib>, Vec<Arc<Package>>>,
+) -> anyhow::Result<()> {
+    let fingerprint = match db_fingerprint(db) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            tracing::warn!("not writing the lockfile: {:#}", e);
+            return Ok(());
+        }
+    };
+    let lockfile = Lockfile::new(fingerprint, missing_map);
+    fs::write(path, toml::to_string(&lockfile)?)
+        .with_context(|| format!("unable to write {}", path.display()))
+}
+
+/// Reads a TOML table mapping sonames to package attributes, e.g.
+/// `"libz.so.1" = "zlib"`
+fn read_mapping_file(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("unable to parse {}", path.display()))
+}
+
+/// Checks whether a file starts with the ELF magic bytes
+fn is_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == b"\x7fELF"
+}
+
+/// Reads the architecture from the ELF header's `e_machine`, named like Rust's
+/// `std::env::consts::ARCH` except for `i386`
+fn detect_elf_arch(binary: &Path) -> anyhow::Result<&'static str> {
+    let mut header = [0u8; 20];
+    fs::File::open(binary)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .with_context(|| format!("unable to read the ELF header of {}", binary.display()))?;
+    if &header[..4] != b"\x7fELF" {
+        anyhow::bail!("{} is not an ELF file", binary.display());
+    }
+    // EI_DATA tells the byte order of the following fields
+    let machine = match header[5] {
+        2 => u16::from_be_bytes([header[18], header[19]]),
+        _ => u16::from_le_bytes([header[18], header[19]]),
+    };
+    Ok(match machine {
+        2 => "sparc",
+        43 => "sparc64",
+        3 => "i386",
+        8 => "mips",
+        20 => "powerpc",
+        21 => "powerpc64",
+        22 => "s390x",
+        40 => "arm",
+        62 => "x86_64",
+        183 => "aarch64",
+        243 => "riscv64",
+        258 => "loongarch64",
+        _ => "unknown",
+    })
+}
+
+/// Checks that the binary is a readable regular file, which is either an ELF
+/// file or at least executable
+fn validate_binary(path: &Path) -> anyhow::Result<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Failure::BinaryNotFound(path.to_path_buf()).into())
+        }
+        Err(e) => return Err(e).with_context(|| format!("unable to stat {}", path.display())),
+    };
+    if !metadata.is_file() {
+        anyhow::bail!("{} is not a regular file", path.display());
+    }
+    fs::File::open(path).with_context(|| format!("unable to read {}", path.display()))?;
+    if !is_elf(path) && metadata.permissions().mode() & 0o111 == 0 {
+        anyhow::bail!("{} is neither an ELF file nor executable", path.display());
+    }
+    Ok(())
+}
+
+/// Recursively collects the regular ELF files within a directory. Everything
+/// else, including symlinks, sockets, FIFOs and device nodes, is skipped.
+fn elf_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() && is_elf(&path) {
+                files.push(path);
+            } else {
+                tracing::info!("skipping {}, not a regular ELF file", path.display());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// uses ldd to find missing shared object files on a given binary
+fn missing_libs(binary: &Path) -> anyhow::Result<Vec<MissingLib>> {
+    let ldd = std::env::var_os(LDD_ENV).unwrap_or_else(|| "ldd".into());
+    tracing::debug!("running {:?} on {}", ldd, binary.display());
+    let output = Command::new(&ldd).arg(binary.as_os_str()).output()?;
+
+    let mut libs = parse_ldd_output(&String::from_utf8(output.stdout)?);
+    // musl's ldd reports missing libs as errors on stderr
+    libs.extend(parse_ldd_output(&String::from_utf8_lossy(&output.stderr)));
+
+    if !output.status.success() && libs.is_empty() {
+        anyhow::bail!("ldd returned error code {}", output.status);
+    }
+
+    Ok(libs)
+}
+
+/// Determines the binary of a running process
+fn pid_binary(pid: u32) -> anyhow::Result<PathBuf> {
+    let proc = PathBuf::from(format!("/proc/{}", pid));
+    if !proc.is_dir() {
+        anyhow::bail!("no process with pid {}", pid);
+    }
+    fs::read_link(proc.join("exe"))
+        .with_context(|| format!("unable to read the executable of process {}", pid))
+}
+
+/// Collects the shared objects a running process has mapped from outside the
+/// nix store, including those loaded via `dlopen`
+fn pid_mapped_libs(pid: u32) -> anyhow::Result<Vec<MissingLib>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .with_context(|| format!("unable to read the mappings of process {}", pid))?;
+    let mut libs: Vec<_> = maps
+        .lines()
+        // the path is the sixth and last field, anonymous mappings lack it
+        .filter_map(|line| line.splitn(6, char::is_whitespace).nth(5))
+        .map(str::trim)
+        .filter(|path| path.starts_with('/') && !path.starts_with("/nix/store/"))
+        .filter_map(|path| path.rsplit('/').next())
+        .filter(|name| name.contains(".so"))
+        .map(|name| MissingLib {
+            name: name.to_string(),
+        })
+        .collect();
+    libs.sort();
+    libs.dedup();
+    Ok(libs)
+}
+
+/// finds sonames embedded as strings in the binary, which hints at libs loaded
+/// via `dlopen` that ldd does not know about. Each pattern captures the lib in
+/// its first group, or else in the whole match, of which only the file name
+/// is kept.
+fn dlopen_libs(binary: &Path, patterns: &[Regex]) -> anyhow::Result<Vec<MissingLib>> {
+    let content = fs::read(binary)?;
+    let default = Regex::new(DLOPEN_PATTERN).expect("the default dlopen pattern is valid");
+    let mut libs = Vec::new();
+    for pattern in std::iter::once(&default).chain(patterns) {
+        for captures in pattern.captures_iter(&content) {
+            let m = captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .expect("a match has a group 0");
+            let path = String::from_utf8_lossy(m.as_bytes());
+            let name = path.rsplit('/').next().unwrap_or_default();
+            if !name.is_empty() {
+                libs.push(MissingLib {
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+    Ok(libs)
+}
+
+/// extracts the missing shared object files from the output of ldd
+fn parse_ldd_output(output: &str) -> Vec<MissingLib> {
+    output.lines().filter_map(parse_ldd_line).collect()
+}
+
+/// extracts a missing shared object file from a line of ldd output. Exactly
+/// two line shapes yield a missing lib:
+///
+/// - glibc: `\tlibfoo.so.1 => not found`
+/// - musl, only considered without `=>` in the line: `Error loading shared
+///   library libfoo.so.1: No such file or directory (needed by ...)`
+///
+/// Everything else is ignored, in particular resolved libs (`libc.so.6 =>
+/// /lib/libc.so.6 (0x...)`), the vdso (`linux-vdso.so.1 (0x...)`), the loader
+/// given by absolute path (`/lib64/ld-linux-x86-64.so.2 (0x...)`) and notes like
+/// `statically linked`. Names which are not a plain file name, or denote the
+/// kernel provided vdso, are rejected even if they appear in one of the shapes.
+fn parse_ldd_line(line: &str) -> Option<MissingLib> {
+    let name = if line.contains("=>") {
+        &line[..line.find(LDD_NOT_FOUND)?]
+    } else {
+        let rest = line.trim().strip_prefix(LDD_MUSL_ERROR)?;
+        &rest[..rest.find(':')?]
+    }
+    .trim();
+
+    let is_file_name = !name.is_empty() && !name.contains(|c: char| c == '/' || c.is_whitespace());
+    let is_vdso = name.starts_with("linux-vdso.so") || name.starts_with("linux-gate.so");
+    (is_file_name && !is_vdso).then(|| MissingLib {
+        name: name.to_string(),
+    })
+}
+
+/// A missing library, identified by the filename (without preceding dirnames),
+/// or a path relative to some lib dir, e.g. `vdpau/libvdpau_nvidia.so`
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct MissingLib {
+    name: String,
+}
+
+/// Renders the soname of the missing library
+impl std::fmt::Display for MissingLib {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// Reads the soname of a shared library file through `readelf -d`
+impl TryFrom<PathBuf> for MissingLib {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> anyhow::Result<Self> {
+        if !is_elf(&path) {
+            anyhow::bail!("{} is no ELF file", path.display());
+        }
+        let output = Command::new("readelf")
+            .arg("-d")
+            .arg(&path)
+            .output()
+            .context("unable to run readelf")?;
+        if !output.status.success() {
+            anyhow::bail!("readelf returned error code {}", output.status);
+        }
+        // e.g. ` 0x000000000000000e (SONAME)  Library soname: [libz.so.1]`
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|l| l.contains("(SONAME)"))
+            .find_map(|l| {
+                let start = l.find('[')? + 1;
+                let end = l.rfind(']')?;
+                Some(MissingLib {
+                    name: l.get(start..end)?.to_string(),
+                })
+            })
+            .ok_or_else(|| anyhow::format_err!("{} has no soname", path.display()))
+    }
+}
+
+/// Returns the regex matching paths of files named `name`, or only starting
+/// with it given `prefix_match`
+fn soname_regex(name: &str, prefix_match: bool) -> Result<Regex, regex::Error> {
+    // sonames are literals, `libstdc++.so.6` must not be read as a regex
+    let regex = format!("/{}", regex::escape(name));
+    if prefix_match {
+        Regex::new(&regex)
+    } else {
+        Regex::new(&format!("{}$", regex))
+    }
+}
+
+impl MissingLib {
+    /// the soname for display, with at most one version component given
+    /// `--strip-version`
+    fn display_name(&self, strip_version: bool) -> String {
+        if strip_version {
+            strip_soname_version(&self.name)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// uses nix-locate to find candidate packages providing a given file,
+    /// identified by a file name, or the trailing components of its path
+    fn find_candidates(&self, db: &Database) -> anyhow::Result<Vec<Package>> {
+        let regex = soname_regex(&self.name, db.prefix_match)
+            .with_context(|| format!("invalid database query for {}", self.name))?;
+        let hits = db.query(&regex)?;
+        tracing::debug!("{} matches {} files", self.name, hits.len());
+        Ok(providing_packages(hits, db.prefer_regular_files))
+    }
+
+    /// finds files with the same stem but a different version, e.g.
+    /// `libssl.so.1.1` for `libssl.so.3`, together with their packages
+    fn find_near_matches(&self, db: &Database) -> anyhow::Result<Vec<(String, Package)>> {
+        let file_name = self.name.rsplit('/').next().unwrap_or_default();
+        let stem = file_name.split(".so").next().unwrap_or_default();
+        let regex = Regex::new(&format!(r"/{}\.so[^/]*$", regex::escape(stem)))?;
+        let mut matches: Vec<_> = db
+            .query(&regex)?
+            .into_iter()
+            .map(|(p, path, _)| {
+                let path = String::from_utf8_lossy(&path);
+                let file = path.rsplit('/').next().unwrap_or_default().to_string();
+                (file, p)
+            })
+            .collect();
+        matches.sort();
+        matches.dedup();
+        Ok(matches)
+    }
+
+    /// finds packages providing the static counterpart of the lib, e.g.
+    /// `libssl.a` for `libssl.so.3`
+    fn find_static_candidates(&self, db: &Database) -> anyhow::Result<Vec<Package>> {
+        let file_name = self.name.rsplit('/').next().unwrap_or_default();
+        let stem = file_name.split(".so").next().unwrap_or_default();
+        let regex = Regex::new(&format!(r"/{}\.a$", regex::escape(stem)))?;
+        Ok(db.query(&regex)?.into_iter().map(|(p, _, _)| p).collect())
+    }
+}
+
+/// The packages of the files found in the database. With `prefer_regular`,
+/// packages providing the file only as symlink are dropped, unless no package
+/// provides it as regular file.
+fn providing_packages(hits: Vec<(Package, Vec<u8>, bool)>, prefer_regular: bool) -> Vec<Package> {
+    // symlinks are often only a versioned alias a package provides on top
+    let regular = prefer_regular && hits.iter().any(|(_, _, regular)| *regular);
+    hits.into_iter()
+        .filter(|(_, _, r)| *r || !regular)
+        .map(|(p, _, _)| p)
+        .collect()
+}
+
+/// A nix-index database, along with the constraints for querying it
+#[derive(Debug, Clone)]
+struct Database {
+    path: PathBuf,
+
+    /// only packages built for this platform are considered, as far as the
+    /// database records the platform
+    system: Option<String>,
+
+    /// how long opening the database may take, before it is considered hung,
+    /// e.g. on a network filesystem
+    open_timeout: Duration,
+
+    /// only consider packages providing a lib as symlink, if no package
+    /// provides it as regular file
+    prefer_regular_files: bool,
+
+    /// match files merely starting with the lib's name, e.g. `libfoo.so.1` for
+    /// `libfoo.so`
+    prefix_match: bool,
+}
+
+impl Database {
+    /// opens the nix-index database on a separate thread, giving up after the
+    /// open timeout
+    fn open(&self) -> anyhow::Result<nix_index::database::Reader> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let path = self.path.clone();
+        std::thread::spawn(move || {
+            // the receiver is gone if the open timed out
+            let _ = tx.send(nix_index::database::Reader::open(&path));
+        });
+        match rx.recv_timeout(self.open_timeout) {
+            Ok(reader) => reader.map_err(|_| anyhow::format_err!("oh no, a nix-index error")),
+            Err(_) => anyhow::bail!(
+                "nix-index database open timed out after {}ms at path: {}. Is the database on a \
+                slow filesystem?",
+                self.open_timeout.as_millis(),
+                self.path.display()
+            ),
+        }
+    }
+
+    /// runs a query against the nix-index database, returning the package, the
+    /// path of each matching file and whether it is a regular file
+    fn query(&self, regex: &Regex) -> anyhow::Result<Vec<(Package, Vec<u8>, bool)>> {
+        let db = self.open()?;
+        db.query(regex)
+            .run()
+            .map_err(|_| anyhow::format_err!("oh no, a nix-index error"))?
+            .filter_map(|x| match x {
+                Ok((store_path, entry)) => self.package_of(&store_path.origin()).map(|package| {
+                    let regular = matches!(entry.node, nix_index::files::FileNode::Regular { .. });
+                    Ok((package, entry.path, regular))
+                }),
+                Err(_) => Some(Err(anyhow::format_err!("oh no, a nix-index error"))),
+            })
+            .collect()
+    }
+
+    /// the package a store path in the database belongs to, unless the path is
+    /// no toplevel path or built for another platform
+    fn package_of(&self, origin: &nix_index::package::PathOrigin) -> Option<Package> {
+        let foreign = match (&self.system, &origin.system) {
+            (Some(wanted), Some(system)) => wanted != system,
+            _ => false,
+        };
+        // non-toplevel paths were only found as dependencies of the attr, which
+        // doesn't necessarily refer to them
+        (origin.toplevel && !foreign)
+            .then(|| Package::from_nix_index_entry(&origin.attr, &origin.output))
+    }
+}
+
+/// A package providing a lib, identified by its attribute and output
+#[derive(Debug, Clone, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct Package {
+    attr: String,
+    output: String,
+}
+
+impl Package {
+    /// a package as recorded by nix-index, where each store path's
+    /// `PathOrigin` carries the attribute and the output of the package
+    /// producing it
+    fn from_nix_index_entry(attr: &str, output: &str) -> Self {
+        Self {
+            attr: attr.to_string(),
+            output: output.to_string(),
+        }
+    }
+
+    /// a package given by the user, which refers to the default output unless
+    /// the attribute names one, e.g. `zlib.dev`
+    fn from_attr(attr: String) -> Self {
+        const OUTPUTS: [&str; 4] = ["out", "lib", "dev", "bin"];
+        match attr.rsplit_once('.') {
+            Some((attr, output)) if OUTPUTS.contains(&output) => Self {
+                attr: attr.to_string(),
+                output: output.to_string(),
+            },
+            _ => Self {
+                attr,
+                output: "out".to_string(),
+            },
+        }
+    }
+
+    /// the attribute path for use in nix expressions, which leaves out the
+    /// default `out` output, e.g. `zlib` but `openssl.dev`
+    fn to_nix_attr(&self) -> String {
+        self.attr_path(self.output != "out")
+    }
+
+    /// the attribute path, quoting components which are no valid identifiers
+    fn attr_path(&self, with_output: bool) -> String {
+        let output = Some(self.output.as_str()).filter(|_| with_output);
+        let path: Vec<_> = self
+            .attr
+            .split('.')
+            .chain(output)
+            .map(|component| {
+                let mut chars = component.chars();
+                let identifier = chars
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c));
+                if identifier {
+                    component.to_string()
+                } else {
+                    format!("\"{}\"", nix_string_escape(component))
+                }
+            })
+            .collect();
+        path.join(".")
+    }
+}
+
+/// Renders the attribute path of the package output, e.g. `zlib.out` or
+/// `python3Packages.numpy.out`, quoting components which are no valid
+/// identifiers, e.g. `"gtk+3".out`
+impl std::fmt::Display for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.attr_path(true))
+    }
+}
+
+/// Two packages are equal if both their attribute and output match. This is
+/// what dedups `Arc<Package>`s, as the comparison of `Arc`s compares the
+/// pointees, not the pointers.
+impl PartialEq for Package {
+    fn eq(&self, other: &Self) -> bool {
+        self.attr == other.attr && self.output == other.output
+    }
+}
+
+impl Eq for Package {}
+
+/// Consistent with [`PartialEq`], hashing both the attribute and the output
+impl std::hash::Hash for Package {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.attr.hash(state);
+        self.output.hash(state);
+    }
+}
+
+#[derive(clap::Parser)]
+#[clap(
+    author,
+    about,
+    after_help = AFTER_HELP,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Opts {
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+
+    /// dynamically linked binary to be examined
+    #[clap(required_unless_present = "pid")]
+    binary: Option<PathBuf>,
+
+    /// examine the binary of a running process instead, additionally
+    /// resolving the libs it has loaded from outside the nix store
+    #[clap(long, conflicts_with = "binary")]
+    pid: Option<u32>,
+
+    /// additional shared object files to search for and propagate, either a
+    /// soname or a path relative to the lib dir, e.g. `vdpau/libvdpau_nvidia.so`.
+    /// Existing library files are looked up by their soname.
+    #[clap(short, long = "lib")]
+    libs: Vec<String>,
+
+    /// file with additional shared object files, one per line, `#` starts a
+    /// comment
+    #[clap(long, value_name = "FILE")]
+    libs_file: Option<PathBuf>,
+
+    /// directories whose ELF files are scanned for missing libs as well, e.g.
+    /// bundled plugins
+    #[clap(long = "scan-dir", value_name = "DIR")]
+    scan_dirs: Vec<PathBuf>,
+
+    /// number of files scanned concurrently, defaults to the number of CPUs
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// use previously captured ldd output instead of running ldd on the binary
+    #[clap(long, value_name = "FILE")]
+    ldd_output: Option<PathBuf>,
+
+    /// skip running ldd on the binary, resolving only the given libs
+    #[clap(long, conflicts_with = "ldd-output")]
+    no_auto_libs: bool,
+
+    /// report the architecture of the binary, warning if it is not the host's
+    #[clap(long)]
+    detect_arch: bool,
+
+    /// additionally scan the binary for sonames loaded via dlopen
+    #[clap(long)]
+    scan_dlopen: bool,
+
+    /// additionally look up the providers of the libs' static counterparts,
+    /// e.g. `libfoo.a`, which are listed in a comment of the expression
+    #[clap(long)]
+    include_static: bool,
+
+    /// additional regex for --scan-dlopen, capturing the lib in group 1 or
+    /// else in the whole match, of which the file name is taken
+    #[clap(
+        long = "dlopen-pattern",
+        value_name = "REGEX",
+        requires = "scan-dlopen"
+    )]
+    dlopen_patterns: Vec<Regex>,
+
+    /// additional packages to propagate
+    #[clap(short, long = "pkg")]
+    pkgs: Vec<String>,
+
+    /// file with additional packages, one per line, `#` starts a comment
+    #[clap(long, value_name = "FILE")]
+    pkgs_file: Option<PathBuf>,
+
+    /// TOML file mapping sonames to package attributes, which takes
+    /// precedence over the nix-index database
+    #[clap(long, value_name = "FILE")]
+    mapping_file: Option<PathBuf>,
+
+    /// look up the compiler runtime libs like `libgomp.so.1` in the
+    /// database too, instead of taking them from `stdenv.cc.cc.lib`.
+    /// `libgcc_s.so.1` and `libstdc++.so.6` are skipped as part of the FHS
+    /// base regardless, unless `--no-skip-base` is given as well.
+    #[clap(long)]
+    no_builtin_mappings: bool,
+
+    /// file recording the candidates of each lib, from which they are reused
+    /// on the next run unless the nix-index database changed in between
+    #[clap(long, value_name = "FILE")]
+    lockfile: Option<PathBuf>,
+
+    /// never query the nix-index database, libs missing in the mapping file
+    /// remain unresolved
+    #[clap(long)]
+    offline: bool,
+
+    /// take the --lib values as regexes on the database's file paths, each
+    /// matching file name being a lib that needs a package, e.g.
+    /// `/libQt5[^/]*\.so\.5$`. Beware that a loose pattern easily matches
+    /// thousands of files and pulls in their packages.
+    #[clap(long, conflicts_with = "offline")]
+    raw_regex: bool,
+
+    /// warn about libs which failed to be looked up and leave them
+    /// unresolved, instead of aborting
+    #[clap(long, conflicts_with = "strict")]
+    keep_going: bool,
+
+    /// abort without writing anything if any lib remains unresolved
+    #[clap(long)]
+    strict: bool,
+
+    /// format the generated expression with nixpkgs-fmt or alejandra, if
+    /// either is available
+    #[clap(long)]
+    format_expression: bool,
+
+    /// check that the generated expression parses with nix-instantiate, which
+    /// happens by default if nix-instantiate is available
+    #[clap(long)]
+    validate: bool,
+
+    /// skip checking the generated expression
+    #[clap(long, conflicts_with = "validate")]
+    no_validate: bool,
+
+    /// comment each package in the generated expression with the libs it
+    /// was selected for, and the packages of --audio as such
+    #[clap(long)]
+    annotate: bool,
+
+    /// include the PulseAudio, ALSA and PipeWire libs, which binaries tend to
+    /// dlopen unbeknownst to ldd
+    #[clap(long)]
+    audio: bool,
+
+    /// include fontconfig, freetype and a font, refreshing the font cache upon
+    /// launch. A shortcut for the most common reason GUI binaries crash
+    /// silently, which ldd does not reveal.
+    #[clap(long)]
+    fontconfig: bool,
+
+    /// query the database for each lib a few times and print the timings,
+    /// instead of writing a wrapper
+    #[clap(long)]
+    benchmark: bool,
+
+    /// report skipped files and other details on stderr
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// append debug logs to this file, in addition to what stderr shows
+    #[clap(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// suppress warnings
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[clap(long)]
+    print_found_packages: bool,
+
+    /// print the store path of each selected package, as evaluated from
+    /// nixpkgs
+    #[clap(long)]
+    print_store_paths: bool,
+
+    /// print the libs without any candidate as JSON array, the exit code is 4
+    /// if there are any
+    #[clap(long)]
+    print_missing_json: bool,
+
+    /// print the total closure size of the selected packages, which requires
+    /// nix to evaluate and possibly fetch them
+    #[clap(long)]
+    estimate_size: bool,
+
+    /// print the candidates of each lib along with their score
+    #[clap(long)]
+    print_candidates: bool,
+
+    /// print the candidates of each lib along with their score as JSON object,
+    /// unresolved libs map to an empty array
+    #[clap(long)]
+    print_candidates_json: bool,
+
+    /// print the number of candidates of each lib along with the candidates
+    #[clap(long)]
+    print_candidates_summary: bool,
+
+    /// regex on package attributes to favor with the prefer-by-score strategy
+    #[clap(long = "prefer-pkg", value_name = "REGEX")]
+    prefer_pkgs: Vec<regex::Regex>,
+
+    /// regex on package attributes to avoid with the prefer-by-score strategy
+    #[clap(long = "exclude-pkg", value_name = "REGEX")]
+    exclude_pkgs: Vec<regex::Regex>,
+
+    /// drop the libs which only this package provides, rather than pulling it in
+    #[clap(long = "exclude-lib-via-package", value_name = "PKG")]
+    exclude_lib_via_pkgs: Vec<String>,
+
+    /// consider packages providing a lib only as symlink just if no package
+    /// provides it as regular file
+    #[clap(long)]
+    prefer_regular_files: bool,
+
+    /// only match files named exactly like the missing lib (default)
+    #[clap(long, overrides_with = "prefix-match")]
+    exact_match: bool,
+
+    /// also match files merely starting with the missing lib's name, e.g.
+    /// `libfoo.so.1` for `libfoo.so`
+    #[clap(long, overrides_with = "exact-match")]
+    prefix_match: bool,
+
+    /// package output to favor with the prefer-by-score strategy
+    #[clap(long, value_name = "OUTPUT", default_value = "out")]
+    prefer_output: String,
+
+    /// additionally write a shell.nix with the libs, in which `run-binary`
+    /// runs the binary, e.g. `nix-shell --run 'run-binary --help'`
+    #[clap(long)]
+    nix_shell_compat: bool,
+
+    /// shell command to launch in the FHS instead of the binary, e.g. a
+    /// launcher script shipped with the binary
+    #[clap(long, value_name = "COMMAND")]
+    run_script: Option<String>,
+
+    /// build a bare FHS, which opens a bash instead of launching the binary
+    #[clap(long, conflicts_with = "run-script")]
+    no_run_script: bool,
+
+    /// shell to interpret --run-script with, e.g. `bash`, which makes it
+    /// take arbitrary shell snippets
+    #[clap(long, value_name = "SHELL", requires = "run-script")]
+    run_script_shell: Option<String>,
+
+    /// directory to launch the binary in, `auto` being the binary's own
+    #[clap(long, value_name = "DIR")]
+    chdir: Option<String>,
+
+    /// name the binary sees as its `argv[0]`, by default its file name, which
+    /// some binaries locate their resources by
+    #[clap(long, value_name = "NAME")]
+    argv0: Option<String>,
+
+    /// directory to write the generated files to, instead of the binary's
+    #[clap(long, value_name = "DIR", conflicts_with = "wrap-in-place")]
+    output_dir: Option<PathBuf>,
+
+    /// replace the binary by the wrapper script, moving the original aside to
+    /// `<binary>.orig`
+    #[clap(long)]
+    wrap_in_place: bool,
+
+    /// overwrite an existing `<binary>.orig` with --wrap-in-place, unless the
+    /// binary is a wrapper already
+    #[clap(long, requires = "wrap-in-place")]
+    force: bool,
+
+    /// add the packages to the sentinel region of an existing shell.nix or
+    /// flake.nix instead of writing a wrapper script
+    #[clap(long, value_name = "FILE")]
+    append: Option<PathBuf>,
+
+    /// further outputs to include for each resolved package, e.g. `lib,dev`
+    #[clap(
+        long = "extra-output-types",
+        value_name = "OUTPUTS",
+        value_delimiter = ','
+    )]
+    extra_outputs: Vec<String>,
+
+    /// the nixpkgs function used to build the FHS
+    #[clap(arg_enum, long, default_value_t)]
+    fhs_backend: FhsBackend,
+
+    /// additional directories to bind read-only into the FHS, the directory
+    /// of the binary is always bound
+    #[clap(long = "bind-dir", value_name = "PATH")]
+    bind_dirs: Vec<PathBuf>,
+
+    /// additional directories to bind read-write into the FHS, e.g. for
+    /// config or save files. Beware that the binary may then modify or delete
+    /// anything in there, just as if it ran unsandboxed.
+    #[clap(long, value_name = "PATH")]
+    bind_rw: Vec<PathBuf>,
+
+    /// environment variables to set before launching the binary
+    #[clap(long, value_name = "KEY=VALUE", parse(try_from_str = parse_env_var))]
+    env: Vec<(String, String)>,
+
+    /// do not source the default /etc/profile of the FHS, which sets up PATH,
+    /// locales and the like; environment variables have to be set by the
+    /// binary's caller instead. The font cache refresh of --fontconfig
+    /// lives in the profile, too.
+    #[clap(long, conflicts_with_all = &["env", "fontconfig"])]
+    no_profile: bool,
+
+    /// remove the /etc the FHS generates, leaving only the files bound in
+    /// from the host's /etc
+    #[clap(long)]
+    no_etc: bool,
+
+    /// shell commands run in the FHS root after it is built, e.g. to create
+    /// files in /etc, or `@FILE` to read them from a file. They are escaped
+    /// into the nix string, so `${...}` reaches the shell instead of being
+    /// antiquoted by nix.
+    #[clap(long, value_name = "SCRIPT")]
+    extra_build_commands: Option<String>,
+
+    /// resolve libs the FHS base (glibc and the gcc runtime) provides anyways,
+    /// like libc.so.6 or libstdc++.so.6. The gcc runtime libs are then taken
+    /// from `stdenv.cc.cc.lib`, unless `--no-builtin-mappings` is given too.
+    #[clap(long)]
+    no_skip_base: bool,
+
+    /// display sonames with at most one version component
+    #[clap(long)]
+    strip_version: bool,
+
+    /// nix-index database to query, defaults to $NIX_INDEX_DATABASE or else
+    /// $XDG_CACHE_HOME/nix-index, or the target's subdirectory therein with
+    /// --cross
+    #[clap(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// milliseconds opening the nix-index database may take
+    #[clap(long = "timeout-db-open", value_name = "MS", default_value_t = 10000)]
+    db_open_timeout_ms: u64,
+
+    /// warn if the nix-index database is older than this many days
+    #[clap(long, value_name = "DAYS", default_value_t = 30)]
+    max_db_age: u64,
+
+    /// fail instead of warning if the database exceeds --max-db-age
+    #[clap(long)]
+    fail_on_stale_db: bool,
+
+    /// platform to resolve and build for, e.g. `aarch64-linux`, defaults to
+    /// the host's. Candidates can only be resolved for platforms the
+    /// nix-index database contains.
+    #[clap(long, value_name = "PLATFORM")]
+    system: Option<String>,
+
+    /// build the FHS for a foreign architecture, given as attribute of
+    /// pkgsCross, e.g. `aarch64-multiplatform`
+    #[clap(long = "cross", value_name = "TARGET")]
+    cross_target: Option<String>,
+
+    /// nixpkgs overlay files to import nixpkgs with
+    #[clap(long = "nixpkgs-overlay", value_name = "FILE")]
+    nixpkgs_overlays: Vec<PathBuf>,
+
+    /// take the packages inside of a native FHS from pkgsCross, given as its
+    /// attribute, e.g. `aarch64-multiplatform`
+    #[clap(
+        long = "pkgs-cross",
+        value_name = "TARGET",
+        conflicts_with = "cross-target"
+    )]
+    pkgs_cross_target: Option<String>,
+
+    /// shebang line of the generated wrapper script
+    #[clap(long, default_value = DEFAULT_SHEBANG)]
+    shebang: String,
+
+    /// shell the wrapper script is written for, which also picks its shebang
+    #[clap(arg_enum, long, default_value_t)]
+    shell: ShellType,
+
+    /// seconds after which the wrapper script gives up building the FHS, e.g.
+    /// on a machine without access to a binary cache
+    #[clap(long, value_name = "SECONDS")]
+    nix_build_timeout: Option<u64>,
+
+    /// additional argument passed to nix-build by the wrapper script
+    #[clap(long = "nix-build-arg", value_name = "ARG", allow_hyphen_values = true)]
+    nix_build_args: Vec<String>,
+
+    #[clap(arg_enum, short, long, default_value_t)]
+    output_format: Output,
+
+    /// nix-portable binary used by the nix-portable output format
+    #[clap(long, value_name = "PATH")]
+    nix_portable_path: Option<PathBuf>,
+
+    #[clap(arg_enum, short, long, default_value_t)]
+    strategy: Strategy,
+
+    #[clap(arg_enum, long, default_value_t)]
+    sort_packages: PackageOrder,
+
+    #[clap(arg_enum, long, default_value_t)]
+    progress_format: ProgressFormat,
+}
+
+#[derive(clap::Subcommand)]
+enum SubCommand {
+    /// check that ldd, nix-index and nix are set up properly
+    Doctor,
+    /// print the available strategies or output formats
+    List {
+        #[clap(arg_enum)]
+        kind: ListKind,
+    },
+}
+
+#[derive(Clone, clap::ArgEnum)]
+enum ListKind {
+    Strategies,
+    Outputs,
+}
+
+#[derive(Clone, Default, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Output {
+    /// a run-with-nix script, which builds the FHS and launches the binary in
+    /// it
+    #[default]
+    NixShell,
+    /// a run-with-nix script like nix-shell, but preferring nix-portable
+    NixPortable,
+    /// a default.nix, which wraps a copy of the binary with makeWrapper
+    #[clap(alias = "default-nix")]
+    #[serde(alias = "default-nix")]
+    WrapProgram,
+    /// a bundle.nix, which builds a self-contained executable with nix-bundle
+    NixBundle,
+    /// a default.nix, which builds an installable package launching the binary
+    Derivation,
+    /// print the selected packages instead of writing anything
+    PackageList,
+    /// print the validated FHS expression, formatted if a formatter is
+    /// available, instead of writing anything
+    NixFmt,
+    /// run the binary through steam-run, resolving nothing but pulling a
+    /// large closure
+    SteamRun,
+}
+
+#[derive(Clone, Default, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Strategy {
+    /// include every candidate of every lib
+    #[default]
+    TakeAll,
+    /// include the highest scoring candidate of every lib
+    PreferByScore,
+    /// include the candidate of every lib whose attribute resembles the lib's
+    /// name the most, e.g. `libpng` for `libpng.so.16`
+    NameMatch,
+    /// include every candidate, except those providing only a strict subset of
+    /// the libs another candidate provides
+    SubsetElim,
+}
+
+/// The order in which the selected packages are rendered and printed
+#[derive(Clone, Default, clap::ArgEnum)]
+enum PackageOrder {
+    /// by attribute and output
+    #[default]
+    Name,
+    /// by the size of their closure, largest first, as evaluated by nix
+    ClosureSize,
+    /// by the number of libs they provide, most first
+    LibCount,
+}
+
+/// Ranks candidate packages by heuristics on their attribute and output
+struct PackageScorer {
+    prefer: Vec<regex::Regex>,
+    exclude: Vec<regex::Regex>,
+    prefer_output: String,
+}
+
+impl PackageScorer {
+    /// the higher, the more likely the package is the desired provider
+    fn score(&self, p: &Package) -> i32 {
+        let mut score = 0;
+        if !p.attr.contains('.') {
+            score += 10;
+        }
+        if p.output == self.prefer_output {
+            score += 5;
+        } else if let "debug" | "dev" = p.output.as_str() {
+            score -= 5;
+        }
+        if self.prefer.iter().any(|r| r.is_match(&p.attr)) {
+            score += 3;
+        }
+        if self.exclude.iter().any(|r| r.is_match(&p.attr)) {
+            score -= 10;
+        }
+        score
+    }
+
+    /// the highest scoring candidate, ties are broken by name
+    fn best<'a>(&self, candidates: &'a [Arc<Package>]) -> Option<&'a Arc<Package>> {
+        candidates
+            .iter()
+            .min_by(|a, b| self.score(b).cmp(&self.score(a)).then_with(|| a.cmp(b)))
+    }
+
+    /// the candidate whose attribute resembles the lib's name the most, ties
+    /// are broken by score and then by name
+    fn best_by_name<'a>(
+        &self,
+        lib: &MissingLib,
+        candidates: &'a [Arc<Package>],
+    ) -> Option<&'a Arc<Package>> {
+        candidates.iter().min_by(|a, b| {
+            name_similarity(&lib.name, &b.attr)
+                .partial_cmp(&name_similarity(&lib.name, &a.attr))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| self.score(b).cmp(&self.score(a)))
+                .then_with(|| a.cmp(b))
+        })
+    }
+}
+
+/// How much a package attribute resembles a soname, from 0 (not at all) to 1
+/// (identical). Only the stem of the soname (`libpng` of `libpng.so.16`), with
+/// or without its `lib` prefix, and the last component of the attribute
+/// (`libpng` of `xorg.libpng`) are compared, ignoring case.
+fn name_similarity(soname: &str, attr: &str) -> f64 {
+    let stem = soname
+        .split(".so")
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let attr = attr.rsplit('.').next().unwrap_or_default().to_lowercase();
+
+    let similarity = |stem: &str| {
+        let (shorter, longer) = if stem.len() < attr.len() {
+            (stem, attr.as_str())
+        } else {
+            (attr.as_str(), stem)
+        };
+        if longer.is_empty() {
+            0.0
+        } else if longer.contains(shorter) {
+            shorter.len() as f64 / longer.len() as f64
+        } else {
+            // half weight for a merely common prefix
+            let common = stem.chars().zip(attr.chars()).take_while(|(a, b)| a == b);
+            0.5 * common.count() as f64 / longer.len() as f64
+        }
+    };
+    let without_prefix = stem.strip_prefix("lib").unwrap_or(&stem);
+    similarity(&stem).max(similarity(without_prefix))
+}
+
+/// A scored candidate as printed by `--print-candidates-json`
+#[derive(serde::Serialize)]
+struct CandidateJson<'a> {
+    package: &'a Package,
+    score: i32,
+}
+
+/// Summarizes the candidates, one line of at most [`SUMMARY_WIDTH`] characters
+/// per lib, e.g. `libfoo.so.1 -> 3 candidates: foo.out bar.out baz.out`
+fn format_candidates_summary(missing_map: &HashMap<Arc<MissingLib>, Vec<Arc<Package>>>) -> String {
+    let mut libs: Vec<_> = missing_map.iter().collect();
+    libs.sort();
+    let mut summary = String::new();
+    for (lib, packages) in libs {
+        let mut packages: Vec<_> = packages.iter().map(|p| p.to_string()).collect();
+        packages.sort();
+        let mut line = format!(
+            "{} -> {} candidate{}:",
+            lib,
+            packages.len(),
+            if packages.len() == 1 { "" } else { "s" }
+        );
+        for (i, package) in packages.iter().enumerate() {
+            let remaining = packages.len() - i;
+            let more = format!(" ...and {} more", remaining);
+            // the last package needs no room for the ellipsis
+            let reserved = if remaining > 1 { more.len() } else { 0 };
+            if line.len() + 1 + package.len() + reserved > SUMMARY_WIDTH {
+                line += &more;
+                break;
+            }
+            line += " ";
+            line += package;
+        }
+        summary += &line;
+        summary.push('\n');
+    }
+    summary
+}
+
+/// Everything learned about a binary, from which all outputs are written
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct ResolutionResult {
+    binary: PathBuf,
+
+    /// the libs which were looked up, sorted
+    missing_libs: Vec<MissingLib>,
+
+    /// the candidates of each lib, empty for unresolved libs
+    candidates: HashMap<MissingLib, Vec<Package>>,
+
+    /// the packages to include, deduplicated and ordered by --sort-packages
+    selected: Vec<Package>,
+
+    /// the packages providing static counterparts of the libs, sorted and
+    /// deduplicated
+    static_packages: Vec<Package>,
+
+    /// the libs without any candidate, sorted
+    unresolved: Vec<MissingLib>,
+}
+
+impl ResolutionResult {
+    /// Returns the libs each candidate package provides
+    fn provided_libs(&self) -> HashMap<&Package, Vec<&MissingLib>> {
+        let mut provided: HashMap<_, Vec<_>> = HashMap::new();
+        for (lib, packages) in &self.candidates {
+            for package in packages {
+                provided.entry(package).or_default().push(lib);
+            }
+        }
+        provided
+    }
+
+    /// Fails with [`Failure::Unresolved`] if any lib is left without
+    /// candidates
+    fn check_resolved(&self) -> anyhow::Result<()> {
+        if !self.unresolved.is_empty() {
+            return Err(Failure::Unresolved(self.unresolved.len()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Timings of repeated database queries for a lib
+struct QueryBenchmark {
+    lib_name: String,
+    min_ms: f64,
+    max_ms: f64,
+    mean_ms: f64,
+    candidate_count: usize,
+}
+
+/// Queries the candidates of each lib [`BENCHMARK_RUNS`] times, one query after
+/// the other, so that the queries don't compete for the disk. The result is
+/// sorted by the mean duration, slowest first.
+fn benchmark_queries(libs: &[MissingLib], db: &Database) -> anyhow::Result<Vec<QueryBenchmark>> {
+    let mut benchmarks = Vec::new();
+    for lib in libs {
+        check_cancelled()?;
+        let mut durations = Vec::new();
+        let mut candidate_count = 0;
+        for _ in 0..BENCHMARK_RUNS {
+            let start = Instant::now();
+            candidate_count = lib.find_candidates(db)?.len();
+            durations.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        benchmarks.push(QueryBenchmark {
+            lib_name: lib.name.clone(),
+            min_ms: durations.iter().copied().fold(f64::INFINITY, f64::min),
+            max_ms: durations.iter().copied().fold(0.0, f64::max),
+            mean_ms: durations.iter().sum::<f64>() / durations.len() as f64,
+            candidate_count,
+        });
+    }
+    benchmarks.sort_by(|a, b| {
+        b.mean_ms
+            .partial_cmp(&a.mean_ms)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(benchmarks)
+}
+
+/// Candidate packages per lib, together with the time each successful database
+/// query took. Libs whose lookup failed have no candidates and no timing.
+type Lookup = (
+    HashMap<Arc<MissingLib>, Vec<Arc<Package>>>,
+    Vec<(MissingLib, Duration)>,
+);
+
+/// Looks up the candidates of a lib, with `keep_going` a failed lookup is
+/// only warned about and yields `None`
+fn find_candidates_or_warn(
+    lib: &MissingLib,
+    db: &Database,
+    keep_going: bool,
+) -> anyhow::Result<Option<Vec<Package>>> {
+    match lib.find_candidates(db) {
+        Err(e) if keep_going => {
+            tracing::warn!("unable to look up {}: {:#}", lib, e);
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+/// Queries the database with a user supplied regex, taking the name of every
+/// matching file as a lib provided by the packages containing it
+fn raw_regex_candidates(
+    regex: &Regex,
+    db: &Database,
+) -> anyhow::Result<HashMap<MissingLib, Vec<Package>>> {
+    let mut libs: HashMap<MissingLib, Vec<Package>> = HashMap::new();
+    for (package, path, _) in db.query(regex)? {
+        let path = String::from_utf8_lossy(&path);
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        libs.entry(MissingLib { name }).or_default().push(package);
+    }
+    for packages in libs.values_mut() {
+        packages.sort();
+        packages.dedup();
+    }
+    Ok(libs)
+}
+
+/// Returns the packages of which no other package provides a strict superset
+/// of libs
+fn remove_dominated_packages(
+    candidates_map: &HashMap<Arc<Package>, Vec<Arc<MissingLib>>>,
+) -> Vec<Arc<Package>> {
+    let sets: Vec<(&Arc<Package>, std::collections::HashSet<&Arc<MissingLib>>)> = candidates_map
+        .iter()
+        .map(|(p, libs)| (p, libs.iter().collect()))
+        .collect();
+    sets.iter()
+        .filter(|(_, libs)| {
+            !sets
+                .iter()
+                .any(|(_, other)| libs.len() < other.len() && libs.is_subset(other))
+        })
+        .map(|(p, _)| Arc::clone(p))
+        .collect()
+}
+
+/// Looks up the candidate packages of all libs in parallel on the rayon pool
+#[cfg(not(feature = "tokio"))]
+fn lookup_candidates(
+    libs: &[MissingLib],
+    db: &Database,
+    keep_going: bool,
+    reporter: Arc<dyn ProgressReporter>,
+) -> anyhow::Result<Lookup> {
+    use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+    let results = libs
+        .par_iter()
+        .map(|l| {
+            check_cancelled()?;
+            let start = Instant::now();
+            let candidates = find_candidates_or_warn(l, db, keep_going)?;
+            let elapsed = start.elapsed();
+            reporter.lib_resolved(l, candidates.as_ref().map_or(0, Vec::len));
+            Ok((l.clone(), candidates, elapsed))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut missing_map = HashMap::new();
+    let mut timings = Vec::new();
+    for (lib, candidates, elapsed) in results {
+        if candidates.is_some() {
+            timings.push((lib.clone(), elapsed));
+        }
+        missing_map.insert(
+            Arc::new(lib),
+            candidates.into_iter().flatten().map(Arc::new).collect(),
+        );
+    }
+    Ok((missing_map, timings))
+}
+
+/// Looks up the candidate packages of all libs on a tokio runtime, running the
+/// blocking nix-index queries with bounded concurrency
+#[cfg(feature = "tokio")]
+fn lookup_candidates(
+    libs: &[MissingLib],
+    db: &Database,
+    keep_going: bool,
+    reporter: Arc<dyn ProgressReporter>,
+) -> anyhow::Result<Lookup> {
+    use tokio::{
+        sync::{mpsc, Semaphore},
+        task::{self, JoinSet},
+    };
+
+    let concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Arc<MissingLib>, usize)>();
+        let progress = task::spawn(async move {
+            while let Some((lib, candidates)) = rx.recv().await {
+                reporter.lib_resolved(&lib, candidates);
+            }
+        });
+
+        let mut queries = JoinSet::new();
+        for lib in libs.iter().cloned() {
+            let semaphore = semaphore.clone();
+            let tx = tx.clone();
+            let db = db.clone();
+            queries.spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                check_cancelled()?;
+                let lib = Arc::new(lib);
+                let (candidates, elapsed) = task::spawn_blocking({
+                    let lib = lib.clone();
+                    move || {
+                        let start = Instant::now();
+                        find_candidates_or_warn(&lib, &db, keep_going).map(|c| (c, start.elapsed()))
+                    }
+                })
+                .await??;
+                // the receiver only goes away once all senders are dropped
+                let _ = tx.send((lib.clone(), candidates.as_ref().map_or(0, Vec::len)));
+                Ok::<_, anyhow::Error>((lib, candidates, elapsed))
+            });
+        }
+        drop(tx);
+
+        let mut missing_map = HashMap::new();
+        let mut timings = Vec::new();
+        while let Some(result) = queries.join_next().await {
+            let (lib, candidates, elapsed) = result??;
+            if candidates.is_some() {
+                timings.push((MissingLib::clone(&lib), elapsed));
+            }
+            missing_map.insert(
+                lib,
+                candidates.into_iter().flatten().map(Arc::new).collect(),
+            );
+        }
+        progress.await?;
+        Ok((missing_map, timings))
+    })
+}
+
+#[derive(Clone, Default, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProgressFormat {
+    #[default]
+    Fancy,
+    Ascii,
+    #[clap(name = "jsonl")]
+    #[serde(rename = "jsonl")]
+    JsonLines,
+    None,
+}
+
+/// The phases of a run, as reported to the user
+#[derive(Clone, Copy)]
+enum Phase {
+    Scan,
+    Refine,
+    Lookup,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Scan => "scan",
+            Self::Refine => "refine",
+            Self::Lookup => "lookup",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::Scan => "scanning for missing libs",
+            Self::Refine => "refining missing libs",
+            Self::Lookup => "loooking up candidate packages",
+        }
+    }
+}
+
+/// Receives the notable events of a run, to present them to the user
+trait ProgressReporter: Send + Sync {
+    /// a phase started, consisting of `len` steps if known upfront
+    fn start(&self, phase: Phase, len: Option<u64>);
+
+    /// a file was scanned for missing libs
+    fn file_scanned(&self, file: &Path);
+
+    /// the candidates of a lib were looked up
+    fn lib_resolved(&self, lib: &MissingLib, candidates: usize);
+
+    /// the current phase is done
+    fn finish(&self);
+
+    /// the run is done, resulting in the given number of packages
+    fn complete(&self, selected_packages: usize);
+}
+
+/// Reports progress through indicatif's spinners and progress bars
+struct FancyReporter {
+    ascii: bool,
+    bar: std::sync::Mutex<Option<ProgressBar>>,
+}
+
+impl FancyReporter {
+    fn new(ascii: bool) -> Self {
+        Self {
+            ascii,
+            bar: Default::default(),
+        }
+    }
+}
+
+impl ProgressReporter for FancyReporter {
+    fn start(&self, phase: Phase, len: Option<u64>) {
+        let bar = match len {
+            Some(count) => new_progress(count, phase.message(), self.ascii),
+            None => new_spinner(phase.message(), self.ascii),
+        };
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    fn file_scanned(&self, _: &Path) {
+        if let Some(bar) = self.bar.lock().unwrap().as_ref() {
+            bar.inc(1);
+        }
+    }
+
+    fn lib_resolved(&self, _: &MissingLib, _: usize) {
+        if let Some(bar) = self.bar.lock().unwrap().as_ref() {
+            bar.inc(1);
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish();
+        }
+    }
+
+    fn complete(&self, _: usize) {}
+}
+
+/// A progress event as emitted by the [`JsonLinesReporter`]
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ProgressEvent<'a> {
+    Start {
+        phase: &'static str,
+    },
+    FileScanned {
+        file: &'a Path,
+    },
+    LibResolved {
+        lib: &'a MissingLib,
+        candidates: usize,
+    },
+    Complete {
+        selected_packages: usize,
+    },
+}
+
+/// Reports progress as one JSON object per event on stderr
+struct JsonLinesReporter;
+
+impl JsonLinesReporter {
+    fn emit(&self, event: ProgressEvent) {
+        match serde_json::to_string(&event) {
+            Ok(line) => eprintln!("{}", line),
+            Err(e) => tracing::warn!("unable to serialize progress event: {}", e),
+        }
+    }
+}
+
+impl ProgressReporter for JsonLinesReporter {
+    fn start(&self, phase: Phase, _: Option<u64>) {
+        self.emit(ProgressEvent::Start {
+            phase: phase.name(),
+        });
+    }
+
+    fn file_scanned(&self, file: &Path) {
+        self.emit(ProgressEvent::FileScanned { file });
+    }
+
+    fn lib_resolved(&self, lib: &MissingLib, candidates: usize) {
+        self.emit(ProgressEvent::LibResolved { lib, candidates });
+    }
+
+    fn finish(&self) {}
+
+    fn complete(&self, selected_packages: usize) {
+        self.emit(ProgressEvent::Complete { selected_packages });
+    }
+}
+
+/// Swallows all progress
+struct NoReporter;
+
+impl ProgressReporter for NoReporter {
+    fn start(&self, _: Phase, _: Option<u64>) {}
+    fn file_scanned(&self, _: &Path) {}
+    fn lib_resolved(&self, _: &MissingLib, _: usize) {}
+    fn finish(&self) {}
+    fn complete(&self, _: usize) {}
+}
+
+fn new_spinner(msg: &'static str, ascii: bool) -> ProgressBar {
+    let mut style = ProgressStyle::default_spinner();
+    if ascii {
+        style = style.tick_chars(r"-\|/ ");
+    }
+    ProgressBar::new_spinner()
+        .with_style(style)
+        .with_message(msg)
+        .with_finish(ProgressFinish::AndLeave)
+}
+
+fn new_progress(count: u64, msg: &'static str, ascii: bool) -> ProgressBar {
+    let template = if ascii {
+        "[{elapsed_precise}] {bar:40} {pos:>7}/{len:7} {msg}"
+    } else {
+        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}"
+    };
+    let style = ProgressStyle::default_bar()
+        .template(template)
+        .expect("the progress bar template is valid")
+        .progress_chars("##-");
+    ProgressBar::new(count)
+        .with_style(style)
+        .with_message(msg)
+        .with_finish(ProgressFinish::AndLeave)
+}
+
+/// Defaults for some of the [`Opts`], as read from a config file
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    db: Option<PathBuf>,
+    strategy: Option<Strategy>,
+    output_format: Option<Output>,
+    progress_format: Option<ProgressFormat>,
+    shebang: Option<String>,
+    pkgs: Vec<String>,
+    libs: Vec<String>,
+    extra_output_types: Vec<String>,
+}
+
+impl Config {
+    /// reads the first config file found in the current directory or the
+    /// user's config directory, if any
+    fn load() -> anyhow::Result<Self> {
+        let candidates = std::iter::once(PathBuf::from(CONFIG_FILE))
+            .chain(dirs::config_dir().map(|dir| dir.join(CONFIG_FILE)));
+        for path in candidates {
+            if path.is_file() {
+                return toml::from_str(&fs::read_to_string(&path)?)
+                    .map_err(|e| anyhow::format_err!("invalid config {}: {}", path.display(), e));
+            }
+        }
+        Ok(Self::default())
+    }
+
+    /// fills in all options which were not given on the command line
+    fn apply(self, opts: &mut Opts, matches: &clap::ArgMatches) {
+        let defaulted =
+            |id: &str| matches.value_source(id) == Some(clap::ValueSource::DefaultValue);
+
+        opts.db = opts.db.take().or(self.db);
+        if let (Some(strategy), true) = (self.strategy, defaulted("strategy")) {
+            opts.strategy = strategy;
+        }
+        if let (Some(output_format), true) = (self.output_format, defaulted("output-format")) {
+            opts.output_format = output_format;
+        }
+        if let (Some(progress_format), true) = (self.progress_format, defaulted("progress-format"))
+        {
+            opts.progress_format = progress_format;
+        }
+        if let (Some(shebang), true) = (self.shebang, defaulted("shebang")) {
+            opts.shebang = shebang;
+        }
+        opts.pkgs.extend(self.pkgs);
+        opts.libs.extend(self.libs);
+        opts.extra_outputs.extend(self.extra_output_types);
+    }
+}
+
+/// The classes of failures, which are told apart by the exit code
+#[derive(Debug)]
+enum Failure {
+    BinaryNotFound(PathBuf),
+    Ldd,
+    Unresolved(usize),
+    NixIndex,
+    Cancelled,
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::BinaryNotFound(_) => 2,
+            Self::Ldd => 3,
+            Self::Unresolved(_) => 4,
+            Self::NixIndex => 5,
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BinaryNotFound(path) => write!(f, "binary {} not found", path.display()),
+            Self::Ldd => f.write_str("ldd failed"),
+            Self::Unresolved(count) => write!(f, "{} libraries remain unresolved", count),
+            Self::NixIndex => f.write_str("unable to query the nix-index database"),
+            Self::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Failure {
+    /// Finds the failure class of an error, be it the error itself, attached
+    /// as context via `.context(Failure::..)` or somewhere among its sources
+    fn of(e: &anyhow::Error) -> Option<&Self> {
+        e.downcast_ref::<Self>()
+            .or_else(|| e.chain().find_map(|cause| cause.downcast_ref::<Self>()))
+    }
+}
+
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            let failure = Failure::of(&e);
+            if let Some(Failure::Cancelled) = failure {
+                eprintln!("\nCancelled.");
+            } else {
+                eprintln!("Error: {:?}", e);
+            }
+            std::process::ExitCode::from(failure.map_or(1, Failure::exit_code))
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    // the version names the database, so --db and --cross are needed before
+    // the arguments are parsed for real
+    let early = Opts::command().ignore_errors(true).try_get_matches().ok();
+    let version = build_version_string(
+        early.as_ref().and_then(|m| m.value_of("db")).map(Path::new),
+        early.as_ref().and_then(|m| m.value_of("cross-target")),
+    );
+    let matches = Opts::command().version(version.as_str()).get_matches();
+    let mut opts = Opts::from_arg_matches(&matches)?;
+
+    match &opts.command {
+        Some(SubCommand::Doctor) => {
+            Config::load()?.apply(&mut opts, &matches);
+            return doctor(opts.db.as_deref(), opts.cross_target.as_deref());
+        }
+        Some(SubCommand::List { kind }) => {
+            match kind {
+                ListKind::Strategies => print_variants::<Strategy>(),
+                ListKind::Outputs => print_variants::<Output>(),
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    Config::load()?.apply(&mut opts, &matches);
+    let log_level = if opts.quiet {
+        LevelFilter::ERROR
+    } else if opts.verbose {
+        LevelFilter::INFO
+    } else {
+        LevelFilter::WARN
+    };
+    setup_logging(log_level, opts.log_file.as_deref())?;
+    ctrlc::set_handler(|| CANCELLED.store(true, Ordering::SeqCst))
+        .context("unable to install the signal handler")?;
+    let binary = match opts.pid {
+        Some(pid) => pid_binary(pid)?,
+        None => opts
+            .binary
+            .take()
+            .expect("clap requires a binary without subcommand"),
+    };
+
+    // initilizes packages list and adds additional-packages right away, if
+    // provided
+
+    opts.pkgs.dedup();
+
+    let mut packages_included: Vec<_> = std::mem::take(&mut opts.pkgs)
+        .into_iter()
+        .map(|attr| Arc::new(Package::from_attr(attr)))
+        .collect();
+    if let Some(file) = &opts.pkgs_file {
+        packages_included.extend(read_pkgs_file(file)?.into_iter().map(Arc::new));
+    }
+    if opts.audio {
+        packages_included.extend(audio_packages().into_iter().map(Arc::new));
+    }
+    if opts.fontconfig {
+        packages_included.extend(fontconfig_packages().into_iter().map(Arc::new));
+    }
+
+    validate_binary(&binary)?;
+
+    if opts.detect_arch {
+        let arch = detect_elf_arch(&binary)?;
+        eprintln!("{} is built for {}", binary.display(), arch);
+        let host = match std::env::consts::ARCH {
+            "x86" => "i386",
+            host => host,
+        };
+        if arch != host {
+            tracing::warn!(
+                "the host is {}, ldd may not be able to examine the binary",
+                host
+            );
+        }
+    }
+
+    if opts.wrap_in_place && !matches!(opts.output_format, Output::NixShell | Output::NixPortable) {
+        anyhow::bail!("--wrap-in-place requires a wrapper script output format");
+    }
+
+    // the generated files reference the binary by its canonical path, so they
+    // may live anywhere
+    let output_dir = match &opts.output_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create {}", dir.display()))?;
+            dir.clone()
+        }
+        None => binary.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+
+    // steam-run brings its own env, so there is nothing to resolve
+    if let Output::SteamRun = opts.output_format {
+        return Ok(write_steam_run_script(
+            &output_dir.join("run-with-nix"),
+            &opts.shebang,
+            &binary.canonicalize()?,
+        )?);
+    }
+
+    let db = Database {
+        path: db_path(opts.db.as_deref(), opts.cross_target.as_deref())?,
+        system: opts.system.clone(),
+        open_timeout: Duration::from_millis(opts.db_open_timeout_ms),
+        prefer_regular_files: opts.prefer_regular_files,
+        prefix_match: opts.prefix_match && !opts.exact_match,
+    };
+
+    // a missing database is reported once it is queried
+    if let (Ok(age), false) = (db_age_days(&db.path), opts.offline) {
+        if age > opts.max_db_age as f64 {
+            let msg = format!(
+                "the nix-index database at {} is {:.1} days old, exceeding {} days",
+                db.path.display(),
+                age,
+                opts.max_db_age
+            );
+            if opts.fail_on_stale_db {
+                anyhow::bail!(msg);
+            }
+            tracing::warn!("{}, consider running nix-index", msg);
+        }
+    }
+
+    let reporter: Arc<dyn ProgressReporter> = match opts.progress_format {
+        ProgressFormat::Fancy => Arc::new(FancyReporter::new(false)),
+        ProgressFormat::Ascii => Arc::new(FancyReporter::new(true)),
+        ProgressFormat::JsonLines => Arc::new(JsonLinesReporter),
+        ProgressFormat::None => Arc::new(NoReporter),
+    };
+
+    // a broken pattern is a usage error, reported before any scanning
+    let raw_patterns = if opts.raw_regex {
+        std::mem::take(&mut opts.libs)
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("invalid --raw-regex pattern {}", p)))
+            .collect::<anyhow::Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+    let mut missing_libs = scan_libs(&opts, &binary, reporter.as_ref())?;
+
+    if opts.benchmark {
+        let benchmarks = benchmark_queries(&missing_libs, &db).context(Failure::NixIndex)?;
+        println!(
+            "{:>10} {:>10} {:>10} {:>10}  lib",
+            "min ms", "max ms", "mean ms", "candidates"
+        );
+        for b in benchmarks {
+            println!(
+                "{:>10.1} {:>10.1} {:>10.1} {:>10}  {}",
+                b.min_ms, b.max_ms, b.mean_ms, b.candidate_count, b.lib_name
+            );
+        }
+        return Ok(());
+    }
+
+    let missing_map = lookup_libs(&opts, &db, &mut missing_libs, &raw_patterns, &reporter)?;
+    let result = select_packages(
+        &opts,
+        &db,
+        binary,
+        missing_libs,
+        missing_map,
+        packages_included,
+        reporter.as_ref(),
+    )?;
+    if opts.strict {
+        result.check_resolved()?;
+    }
+    write_outputs(opts, &result, &output_dir)
+}
+
+/// Collects the libs missing from the binary and the scanned files, as well as
+/// the ones given as options
+fn scan_libs(
+    opts: &Opts,
+    binary: &Path,
+    reporter: &dyn ProgressReporter,
+) -> anyhow::Result<Vec<MissingLib>> {
+    let mut scan_files = Vec::new();
+    for dir in &opts.scan_dirs {
+        scan_files.extend(elf_files(dir)?);
+    }
+    reporter.start(
+        Phase::Scan,
+        (!scan_files.is_empty()).then_some(scan_files.len() as u64),
+    );
+
+    // libs given as path to an existing file are identified by their soname
+    let mut missing_libs: Vec<_> = opts
+        .libs
+        .iter()
+        .cloned()
+        .map(|name| {
+            let path = PathBuf::from(&name);
+            if path.is_file() {
+                MissingLib::try_from(path)
+            } else {
+                Ok(MissingLib { name })
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .chain(match &opts.libs_file {
+            Some(file) => read_libs_file(file)?,
+            None => Vec::new(),
+        })
+        .chain(match &opts.ldd_output {
+            Some(file) => parse_ldd_output(&fs::read_to_string(file)?),
+            None if opts.no_auto_libs => Vec::new(),
+            None => missing_libs(binary).map_err(ldd_failure)?,
+        })
+        .collect();
+    if opts.scan_dlopen {
+        missing_libs.extend(dlopen_libs(binary, &opts.dlopen_patterns)?);
+    }
+    if let Some(pid) = opts.pid {
+        missing_libs.extend(pid_mapped_libs(pid)?);
+    }
+    {
+        use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+        // bounded by --jobs, as each file forks an ldd
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(opts.jobs.unwrap_or(0))
+            .build()?;
+        let scanned = pool.install(|| {
+            scan_files
+                .par_iter()
+                .map(|file| {
+                    check_cancelled()?;
+                    let libs = crate::missing_libs(file).map_err(ldd_failure)?;
+                    reporter.file_scanned(file);
+                    Ok(libs)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
+        missing_libs.extend(scanned.into_iter().flatten());
+    }
+    reporter.finish();
+
+    reporter.start(Phase::Refine, None);
+    missing_libs.sort();
+    missing_libs.dedup();
+    if !opts.no_skip_base {
+        missing_libs.retain(|l| {
+            let base = FHS_BASE_LIBS.contains(&l.name.as_str());
+            if base {
+                tracing::info!("skipping {}, the FHS base provides it", l);
+            }
+            !base
+        });
+    }
+    reporter.finish();
+
+    Ok(missing_libs)
+}
+
+/// Looks up the candidates of each lib, from the mappings, the lockfile or the
+/// nix-index database. Libs only the packages of `--exclude-lib-via-package`
+/// provide are dropped.
+fn lookup_libs(
+    opts: &Opts,
+    db: &Database,
+    missing_libs: &mut Vec<MissingLib>,
+    raw_patterns: &[Regex],
+    reporter: &Arc<dyn ProgressReporter>,
+) -> anyhow::Result<HashMap<Arc<MissingLib>, Vec<Arc<Package>>>> {
+    let mut mapping: HashMap<String, String> = if opts.no_builtin_mappings {
+        HashMap::new()
+    } else {
+        BUILTIN_MAPPINGS
+            .iter()
+            .map(|(lib, attr)| (lib.to_string(), attr.to_string()))
+            .collect()
+    };
+    if let Some(file) = &opts.mapping_file {
+        mapping.extend(read_mapping_file(file)?);
+    }
+    let (mapped_libs, unmapped_libs): (Vec<_>, Vec<_>) = missing_libs
+        .iter()
+        .cloned()
+        .partition(|l| mapping.contains_key(&l.name));
+    let locked = match &opts.lockfile {
+        Some(file) => read_lockfile(file, db)?,
+        None => HashMap::new(),
+    };
+    let (locked_libs, unmapped_libs): (Vec<_>, Vec<_>) = unmapped_libs
+        .into_iter()
+        .partition(|l| locked.contains_key(l));
+    if opts.lockfile.is_some() {
+        tracing::info!(
+            "reusing the candidates of {} libs from the lockfile",
+            locked_libs.len()
+        );
+    }
+
+    reporter.start(Phase::Lookup, Some(unmapped_libs.len() as u64));
+    let lookup = if opts.offline {
+        Ok((
+            unmapped_libs
+                .into_iter()
+                .map(|l| (Arc::new(l), Vec::new()))
+                .collect(),
+            Vec::new(),
+        ))
+    } else {
+        lookup_candidates(&unmapped_libs, db, opts.keep_going, reporter.clone())
+    };
+    reporter.finish();
+    // a cancelled lookup is not a database failure
+    check_cancelled()?;
+    let (mut missing_map, mut timings) = lookup.context(Failure::NixIndex)?;
+    // only what the database answered is locked, not the libs left unresolved
+    // offline or by a failed lookup, nor the mapped and raw regex ones
+    let lockable: std::collections::HashSet<MissingLib> = timings
+        .iter()
+        .map(|(l, _)| l.clone())
+        .chain(locked_libs.iter().cloned())
+        .collect();
+    for lib in mapped_libs {
+        let package = Package::from_attr(mapping[&lib.name].clone());
+        missing_map.insert(Arc::new(lib), vec![Arc::new(package)]);
+    }
+    for lib in locked_libs {
+        let packages = locked[&lib].iter().cloned().map(Arc::new).collect();
+        missing_map.insert(Arc::new(lib), packages);
+    }
+    for pattern in raw_patterns {
+        let matches = raw_regex_candidates(pattern, db).context(Failure::NixIndex)?;
+        if matches.is_empty() {
+            eprintln!("no file matches {}", pattern);
+        }
+        for (lib, packages) in matches {
+            if !opts.no_skip_base && FHS_BASE_LIBS.contains(&lib.name.as_str()) {
+                tracing::info!("skipping {}, the FHS base provides it", lib);
+                continue;
+            }
+            missing_libs.push(lib.clone());
+            missing_map
+                .entry(Arc::new(lib))
+                .or_insert_with(|| packages.into_iter().map(Arc::new).collect());
+        }
+    }
+    missing_libs.sort();
+    missing_libs.dedup();
+    if let Some(file) = &opts.lockfile {
+        let locked_map = missing_map
+            .iter()
+            .filter(|(l, _)| lockable.contains(l.as_ref()))
+            .map(|(l, ps)| (l.clone(), ps.clone()))
+            .collect();
+        write_lockfile(file, db, &locked_map)?;
+    }
+
+    if !timings.is_empty() {
+        // broad sonames can match huge parts of the database, surface them
+        timings.sort_by_key(|t| std::cmp::Reverse(t.1));
+        tracing::info!("slowest database queries:");
+        for (lib, elapsed) in timings.iter().take(10) {
+            tracing::info!("  {:>10.3?}  {}", elapsed, lib);
+        }
+    }
+
+    if opts.print_missing_json {
+        let mut unresolved: Vec<&MissingLib> = missing_map
+            .iter()
+            .filter(|(_, ps)| ps.is_empty())
+            .map(|(l, _)| l.as_ref())
+            .collect();
+        unresolved.sort();
+        println!("{}", serde_json::to_string(&unresolved)?);
+    }
+
+    // only unresolved libs are worth the extra queries for near matches
+    for (lib, _) in missing_map.iter().filter(|(_, ps)| ps.is_empty()) {
+        if opts.offline {
+            eprintln!("no provider found for {}", lib);
+            continue;
+        }
+        let near_matches = lib.find_near_matches(db).context(Failure::NixIndex)?;
+        if near_matches.is_empty() {
+            eprintln!("no provider found for {}", lib);
+            continue;
+        }
+        eprintln!("no provider found for {}, near matches:", lib);
+        for (file, p) in near_matches {
+            eprintln!("  {} provides {}", p, file);
+        }
+    }
+    let unresolved = missing_map.values().filter(|ps| ps.is_empty()).count();
+    if !opts.quiet && !missing_map.is_empty() {
+        eprintln!(
+            "{}/{} libraries had candidates",
+            missing_map.len() - unresolved,
+            missing_map.len()
+        );
+    }
+    if unresolved >= STEAM_RUN_HINT_THRESHOLD {
+        eprintln!(
+            "{} libraries remain unresolved, `--output-format steam-run` might run the binary \
+            regardless",
+            unresolved
+        );
+    }
+
+    drop_libs_provided_only_by(&mut missing_map, &opts.exclude_lib_via_pkgs);
+
+    Ok(missing_map)
+}
+
+/// Drops the libs whose only candidate is one of the packages named by
+/// `--exclude-lib-via-package`, returning them
+fn drop_libs_provided_only_by(
+    missing_map: &mut HashMap<Arc<MissingLib>, Vec<Arc<Package>>>,
+    attrs: &[String],
+) -> Vec<Arc<MissingLib>> {
+    let mut dropped: Vec<Arc<MissingLib>> = missing_map
+        .iter()
+        .filter(|(_, ps)| {
+            ps.first().is_some_and(|first| {
+                attrs.contains(&first.attr) && ps.iter().all(|p| p.attr == first.attr)
+            })
+        })
+        .map(|(l, _)| l.clone())
+        .collect();
+    dropped.sort();
+    for lib in &dropped {
+        tracing::warn!(
+            "dropping {}, as only {} provides it",
+            lib,
+            missing_map[lib][0].attr
+        );
+        missing_map.remove(lib);
+    }
+    dropped
+}
+
+/// Picks the packages to include by `--strategy` among the candidates of each
+/// lib, ordered by `--sort-packages`
+fn select_packages(
+    opts: &Opts,
+    db: &Database,
+    binary: PathBuf,
+    missing_libs: Vec<MissingLib>,
+    missing_map: HashMap<Arc<MissingLib>, Vec<Arc<Package>>>,
+    mut packages_included: Vec<Arc<Package>>,
+    reporter: &dyn ProgressReporter,
+) -> anyhow::Result<ResolutionResult> {
+    packages_included.sort();
+    let candidates_map: HashMap<Arc<Package>, Vec<Arc<MissingLib>>> =
+        missing_map
+            .iter()
+            .fold(HashMap::new(), |mut accum, (l, ps)| {
+                ps.iter()
+                    .for_each(|p| accum.entry(p.clone()).or_insert(Vec::new()).push(l.clone()));
+                accum
+            });
+
+    let scorer = PackageScorer {
+        prefer: opts.prefer_pkgs.clone(),
+        exclude: opts.exclude_pkgs.clone(),
+        prefer_output: opts.prefer_output.clone(),
+    };
+
+    if opts.print_candidates {
+        let mut libs: Vec<_> = missing_map.keys().collect();
+        libs.sort();
+        for lib in libs {
+            let candidates: Vec<_> = missing_map[lib]
+                .iter()
+                .map(|p| format!("{} ({})", p, scorer.score(p)))
+                .collect();
+            println!("{}: {}", lib, candidates.join(" "));
+        }
+    }
+
+    let mut static_packages = Vec::new();
+    if opts.include_static && !opts.offline {
+        use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+        let found = missing_libs
+            .par_iter()
+            .map(|l| l.find_static_candidates(db))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context(Failure::NixIndex)?;
+        static_packages.extend(found.into_iter().flatten());
+        static_packages.sort();
+        static_packages.dedup();
+    }
+
+    if opts.print_candidates_summary && !opts.quiet {
+        print!("{}", format_candidates_summary(&missing_map));
+    }
+
+    if opts.print_candidates_json {
+        let candidates: std::collections::BTreeMap<&MissingLib, Vec<CandidateJson>> = missing_map
+            .iter()
+            .map(|(lib, packages)| {
+                let scored = packages
+                    .iter()
+                    .map(|p| CandidateJson {
+                        package: p,
+                        score: scorer.score(p),
+                    })
+                    .collect();
+                (lib.as_ref(), scored)
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&candidates)?);
+    }
+
+    let selected: Vec<Arc<Package>> = match opts.strategy {
+        Strategy::TakeAll => candidates_map.keys().cloned().collect(),
+        Strategy::PreferByScore => missing_map
+            .values()
+            .filter_map(|ps| scorer.best(ps))
+            .cloned()
+            .collect(),
+        Strategy::NameMatch => missing_map
+            .iter()
+            .filter_map(|(lib, ps)| scorer.best_by_name(lib, ps))
+            .cloned()
+            .collect(),
+        Strategy::SubsetElim => remove_dominated_packages(&candidates_map),
+    };
+    packages_included.extend(
+        selected
+            .iter()
+            .flat_map(|p| expand_package_outputs(p, &opts.extra_outputs))
+            .map(Arc::new),
+    );
+    packages_included = dedup_packages(packages_included);
+    for package in &packages_included {
+        tracing::debug!("selected {}", package);
+    }
+    packages_included.sort();
+    reporter.complete(packages_included.len());
+
+    let mut unresolved: Vec<_> = missing_map
+        .iter()
+        .filter(|(_, ps)| ps.is_empty())
+        .map(|(l, _)| MissingLib::clone(l))
+        .collect();
+    unresolved.sort();
+    let mut result = ResolutionResult {
+        binary,
+        missing_libs,
+        candidates: missing_map
+            .iter()
+            .map(|(l, ps)| {
+                let ps = ps.iter().map(|p| Package::clone(p)).collect();
+                (MissingLib::clone(l), ps)
+            })
+            .collect(),
+        selected: packages_included
+            .iter()
+            .map(|p| Package::clone(p))
+            .collect(),
+        static_packages,
+        unresolved,
+    };
+    // the sorts are stable, so ties stay ordered by name
+    match opts.sort_packages {
+        PackageOrder::Name => {}
+        PackageOrder::ClosureSize => {
+            use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+            let sizes: HashMap<Package, u64> = result
+                .selected
+                .par_iter()
+                .map(|p| {
+                    let size = closure_size(std::slice::from_ref(p)).unwrap_or(0);
+                    (p.clone(), size)
+                })
+                .collect();
+            result.selected.sort_by_key(|p| std::cmp::Reverse(sizes[p]));
+        }
+        PackageOrder::LibCount => {
+            let lib_counts: HashMap<Package, usize> = result
+                .provided_libs()
+                .into_iter()
+                .map(|(p, libs)| (p.clone(), libs.len()))
+                .collect();
+            result
+                .selected
+                .sort_by_key(|p| std::cmp::Reverse(lib_counts.get(p).copied().unwrap_or(0)))
+        }
+    }
+
+    Ok(result)
+}
+
+/// Writes the wrapper, or whatever else `--output-format` asks for, for the
+/// selected packages
+fn write_outputs(opts: Opts, result: &ResolutionResult, output_dir: &Path) -> anyhow::Result<()> {
+    if opts.estimate_size {
+        match closure_size(&result.selected) {
+            Ok(size) => eprintln!("estimated closure size: {}", human_size(size)),
+            Err(e) => eprintln!("unable to estimate the closure size: {:#}", e),
+        }
+    }
+
+    if opts.print_found_packages {
+        println!(
+            "[ {} ]",
+            result
+                .selected
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+
+    if opts.print_store_paths {
+        use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+        let store_paths: Vec<_> = result
+            .selected
+            .par_iter()
+            .map(|p| match resolve_store_path(p) {
+                Ok(path) => format!("{} {}", p, path.display()),
+                Err(_) => format!("{} (unavailable)", p),
+            })
+            .collect();
+        for line in store_paths {
+            println!("{}", line);
+        }
+    }
+
+    // nothing is written, the package list on stdout is all there is
+    if let Output::PackageList = opts.output_format {
+        for package in &result.selected {
+            println!("{}", package);
+        }
+        return result.check_resolved();
+    }
+
+    // last chance to bail out before any file is touched
+    check_cancelled()?;
+
+    if let Some(file) = &opts.append {
+        inject_packages(file, &result.selected)?;
+        return result.check_resolved();
+    }
+
+    // the binary's own directory is bound, so that it finds its assets
+    let canonical_binary = result.binary.canonicalize()?;
+
+    // with --wrap-in-place the wrapper takes the binary's place, running the
+    // original from beside it
+    let orig_binary = opts.wrap_in_place.then(|| {
+        let mut name = canonical_binary
+            .file_name()
+            .expect("a canonical file path has a file name")
+            .to_os_string();
+        name.push(".orig");
+        canonical_binary.with_file_name(name)
+    });
+    if let Some(orig) = &orig_binary {
+        // wrapping a wrapper again would move it onto the original
+        if orig.exists() && !is_elf(&canonical_binary) {
+            anyhow::bail!(
+                "{} is wrapped already, its original is {}",
+                canonical_binary.display(),
+                orig.display()
+            );
+        }
+        if orig.exists() && !opts.force {
+            anyhow::bail!(
+                "refusing to overwrite {}, pass --force to do so",
+                orig.display()
+            );
+        }
+    }
+    let exec_binary = orig_binary.as_deref().unwrap_or(&canonical_binary);
+
+    let mut fhs_options = FhsOptions {
+        env: opts.env.clone(),
+        no_profile: opts.no_profile,
+        no_etc: opts.no_etc,
+        backend: opts.fhs_backend,
+        system: opts.system.clone(),
+        pkgs_cross: opts.pkgs_cross_target.clone(),
+        static_packages: result.static_packages.clone(),
+        extra_build_commands: match opts.extra_build_commands.as_deref() {
+            Some(script) => {
+                let script = match script.strip_prefix('@') {
+                    Some(file) => fs::read_to_string(file)
+                        .with_context(|| format!("unable to read {}", file))?,
+                    None => script.to_string(),
+                };
+                if script.trim().is_empty() {
+                    anyhow::bail!("--extra-build-commands must not be empty");
+                }
+                Some(script)
+            }
+            None => None,
+        },
+        keep_order: !matches!(opts.sort_packages, PackageOrder::Name),
+        run_script: opts
+            .run_script
+            .as_ref()
+            .map(|script| match &opts.run_script_shell {
+                // the shell's $0, the arguments follow
+                Some(shell) => format!("{} -c {} fhs", shell_quote(shell), shell_quote(script)),
+                None => script.clone(),
+            }),
+        argv0: opts.argv0.clone().or_else(|| {
+            canonical_binary
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+        }),
+        chdir: match opts.chdir.as_deref() {
+            Some("auto") => canonical_binary.parent().map(Path::to_path_buf),
+            Some(dir) => Some(
+                Path::new(dir)
+                    .canonicalize()
+                    .with_context(|| format!("unable to change into {}", dir))?,
+            ),
+            None => None,
+        },
+        ..Default::default()
+    };
+    for overlay in &opts.nixpkgs_overlays {
+        if overlay.extension().is_none_or(|e| e != "nix") {
+            anyhow::bail!("overlay {} is no .nix file", overlay.display());
+        }
+        fs::File::open(overlay)
+            .with_context(|| format!("unable to read overlay {}", overlay.display()))?;
+        fhs_options.overlays.push(overlay.canonicalize()?);
+    }
+    if opts.annotate {
+        let provided_libs = result.provided_libs();
+        for package in &result.selected {
+            if let Some(libs) = provided_libs.get(package) {
+                let mut libs: Vec<_> = libs
+                    .iter()
+                    .map(|l| l.display_name(opts.strip_version))
+                    .collect();
+                libs.sort_unstable();
+                libs.dedup();
+                fhs_options
+                    .annotations
+                    .insert(package.clone(), format!("for {}", libs.join(" ")));
+            }
+        }
+    }
+    if opts.fontconfig {
+        fhs_options
+            .profile_snippets
+            .push(fontconfig_profile_script().to_string());
+    }
+    if opts.audio && opts.annotate {
+        for package in audio_packages() {
+            // the audio packages may provide missing libs as well
+            let comment = fhs_options.annotations.entry(package).or_default();
+            if !comment.is_empty() {
+                comment.push_str("; ");
+            }
+            comment.push_str("audio support (--audio flag)");
+        }
+    }
+    let binds = &mut fhs_options.binds;
+    binds.push(
+        canonical_binary
+            .parent()
+            .expect("a canonical file path has a parent")
+            .to_path_buf(),
+    );
+    for dir in &opts.bind_dirs {
+        binds.push(dir.canonicalize()?);
+    }
+    // the binary can only be launched in directories visible in the FHS
+    binds.extend(fhs_options.chdir.clone());
+    binds.sort();
+    binds.dedup();
+    for dir in &opts.bind_rw {
+        fhs_options.rw_binds.push(
+            dir.canonicalize()
+                .with_context(|| format!("unable to bind {}", dir.display()))?,
+        );
+    }
+
+    // build FHS expression
+    let run = Some(exec_binary).filter(|_| !opts.no_run_script);
+    let packages = result.selected.iter();
+    let mut fhs_expression = match &opts.cross_target {
+        Some(target) => fhs_shell_cross(target, run, packages, &fhs_options),
+        None => fhs_shell(run, packages, &fhs_options),
+    };
+    let fmt_only = matches!(opts.output_format, Output::NixFmt);
+    if opts.format_expression || fmt_only {
+        if find_nix_formatter().is_none() {
+            tracing::info!("neither nixpkgs-fmt nor alejandra found, leaving expression as is");
+        }
+        fhs_expression = format_nix_expression(&fhs_expression).map_err(subprocess_failure)?;
+    }
+    let validate = fmt_only
+        || !opts.no_validate
+            && (opts.validate || command_output("nix-instantiate", &["--version"]).is_ok());
+    if validate {
+        validate_nix_expression(&fhs_expression).map_err(subprocess_failure)?;
+    }
+    // a signal may have arrived while the expression was formatted or validated
+    check_cancelled()?;
+    if fmt_only {
+        println!("{}", fhs_expression);
+        return result.check_resolved();
+    }
+    if opts.nix_shell_compat {
+        write_nix_shell_compat(
+            &output_dir.join("shell.nix"),
+            exec_binary,
+            result.selected.iter(),
+        )?;
+    }
+
+    // in place, the wrapper is written aside first and swapped in afterwards
+    let target = match &orig_binary {
+        Some(_) => {
+            let mut name = std::ffi::OsString::from(".");
+            name.push(canonical_binary.file_name().unwrap_or_default());
+            name.push(".nix-autobahn");
+            canonical_binary.with_file_name(name)
+        }
+        None => output_dir.join("run-with-nix"),
+    };
+    let script_options = ScriptOptions {
+        // an explicit --shebang wins over the shell's
+        shebang: if opts.shebang == DEFAULT_SHEBANG {
+            shebang_for_shell(opts.shell).to_string()
+        } else {
+            opts.shebang.clone()
+        },
+        shell: opts.shell,
+        nix_build_timeout: opts.nix_build_timeout,
+        nix_build_args: opts.nix_build_args.clone(),
+    };
+    match opts.output_format {
+        Output::NixShell => {
+            let binfmt_comment = match &opts.cross_target {
+                Some(target) => format!(
+                    "# {target} binaries run through qemu-user, which has to be registered\n\
+                    # with binfmt_misc, e.g. via `boot.binfmt.emulatedSystems` on NixOS\n"
+                ),
+                None => String::new(),
+            };
+            let libs_comment = result
+                .missing_libs
+                .iter()
+                .map(|l| l.display_name(opts.strip_version))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let timeout = script_options.timeout_variable();
+            let exec = script_options.exec_fhs(&fhs_expression);
+            write_bash_script(
+                &target,
+                &script_options.shebang,
+                &format!("{binfmt_comment}# missing libs: {libs_comment}\n{timeout}{exec}"),
+            )?;
+        }
+        Output::NixPortable => write_nix_portable_script(
+            &target,
+            &fhs_expression,
+            opts.nix_portable_path.as_deref(),
+            &script_options,
+        )?,
+        Output::WrapProgram => write_wrap_program_nix(
+            &output_dir.join("default.nix"),
+            &canonical_binary,
+            result.selected.iter(),
+        )?,
+        Output::NixBundle => {
+            write_nix_bundle_expression(&output_dir.join("bundle.nix"), &fhs_expression)?
+        }
+        Output::Derivation => write_derivation_nix(
+            &output_dir.join("default.nix"),
+            exec_binary,
+            &fhs_expression,
+        )?,
+        Output::PackageList => unreachable!("the package list is printed above"),
+        Output::NixFmt => unreachable!("the expression is printed above"),
+        Output::SteamRun => unreachable!("the steam-run wrapper is written above"),
+    }
+
+    // the original is linked aside and the wrapper renamed over it, so that
+    // the binary's path never goes missing
+    if let Some(orig) = &orig_binary {
+        if orig.exists() {
+            fs::remove_file(orig)
+                .with_context(|| format!("unable to remove {}", orig.display()))?;
+        }
+        fs::hard_link(&canonical_binary, orig)
+            .with_context(|| format!("unable to link the binary to {}", orig.display()))?;
+        if let Err(e) = fs::rename(&target, &canonical_binary) {
+            fs::remove_file(orig)?;
+            fs::remove_file(&target)?;
+            return Err(e).context("unable to put the wrapper in place");
+        }
+    }
+
+    result.check_resolved()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix_index::package::PathOrigin;
+
+    #[test]
+    fn failure_attached_as_context_keeps_its_exit_code() {
+        let e = Err::<(), _>(anyhow::anyhow!("no such file"))
+            .context(Failure::Ldd)
+            .context("while scanning")
+            .unwrap_err();
+        assert_eq!(Failure::of(&e).map(Failure::exit_code), Some(3));
+
+        let e = anyhow::Error::from(Failure::Cancelled);
+        assert_eq!(Failure::of(&e).map(Failure::exit_code), Some(130));
+
+        assert!(Failure::of(&anyhow::anyhow!("other")).is_none());
+    }
+
+    #[test]
+    fn lockfile_round_trips_with_unresolved_libs() {
+        let mut missing_map = HashMap::new();
+        missing_map.insert(
+            Arc::new(MissingLib {
+                name: "libz.so.1".to_string(),
+            }),
+            vec![Arc::new(Package::from_attr("zlib".to_string()))],
+        );
+        missing_map.insert(
+            Arc::new(MissingLib {
+                name: "libgone.so.1".to_string(),
+            }),
+            Vec::new(),
+        );
+        missing_map.insert(
+            Arc::new(MissingLib {
+                name: "libstdc++.so.6".to_string(),
+            }),
+            vec![Arc::new(Package {
+                attr: "stdenv.cc.cc".to_string(),
+                output: "lib".to_string(),
+            })],
+        );
+        let lockfile = Lockfile::new("42-1700000000".to_string(), &missing_map);
+
+        let written = toml::to_string(&lockfile).unwrap();
+        let read: Lockfile = toml::from_str(&written).unwrap();
+        assert_eq!(read, lockfile);
+
+        let candidates = read.into_candidates();
+        assert_eq!(candidates.len(), 3);
+        assert!(candidates[&MissingLib {
+            name: "libgone.so.1".to_string()
+        }]
+            .is_empty());
+        assert_eq!(
+            candidates[&MissingLib {
+                name: "libstdc++.so.6".to_string()
+            }],
+            vec![Package {
+                attr: "stdenv.cc.cc".to_string(),
+                output: "lib".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn fhs_backends_render_their_builder() {
+        let options = |backend| FhsOptions {
+            backend,
+            binds: vec![PathBuf::from("/opt/app")],
+            ..Default::default()
+        };
+
+        let user_env = fhs_shell(None, std::iter::empty(), &options(FhsBackend::UserEnv));
+        assert!(user_env.contains("  buildFHSUserEnv {"));
+        assert!(user_env.contains(r#""--ro-bind" "/opt/app" "/opt/app""#));
+
+        let chroot = fhs_shell(None, std::iter::empty(), &options(FhsBackend::Chroot));
+        assert!(chroot.contains("  buildFHSEnvChroot {"));
+        assert!(!chroot.contains("extraBwrapArgs"));
+
+        let bwrap = fhs_shell(None, std::iter::empty(), &options(FhsBackend::Bubblewrap));
+        assert!(bwrap.contains("  buildFHSEnvBubblewrap {"));
+        assert!(bwrap.contains(r#""--ro-bind" "/opt/app" "/opt/app""#));
+
+        let cross = fhs_shell_cross(
+            "aarch64-multiplatform",
+            None,
+            std::iter::empty(),
+            &options(FhsBackend::Chroot),
+        );
+        assert!(cross.contains("  pkgsCross.aarch64-multiplatform.buildFHSEnvChroot {"));
+    }
+
+    #[test]
+    fn progress_format_is_named_jsonl() {
+        use clap::ArgEnum;
+
+        let format = ProgressFormat::from_str("jsonl", false).unwrap();
+        assert!(matches!(format, ProgressFormat::JsonLines));
+
+        #[derive(serde::Deserialize)]
+        struct Config {
+            progress_format: ProgressFormat,
+        }
+        let config: Config = toml::from_str(r#"progress_format = "jsonl""#).unwrap();
+        assert!(matches!(config.progress_format, ProgressFormat::JsonLines));
+    }
+
+    fn package(attr: &str, output: &str) -> Arc<Package> {
+        Arc::new(Package {
+            attr: attr.to_string(),
+            output: output.to_string(),
+        })
+    }
+
+    #[test]
+    fn from_attr_splits_off_a_named_output() {
+        assert_eq!(
+            Package::from_attr("stdenv.cc.cc.lib".to_string()),
+            *package("stdenv.cc.cc", "lib")
+        );
+        assert_eq!(
+            Package::from_attr("zlib".to_string()),
+            *package("zlib", "out")
+        );
+        assert_eq!(
+            Package::from_attr("xorg.libXft".to_string()),
+            *package("xorg.libXft", "out")
+        );
+    }
+
+    #[test]
+    fn dedup_packages_merges_outputs_named_in_the_attribute() {
+        let packages = vec![
+            package("zlib", "out"),
+            package("stdenv.cc.cc", "lib"),
+            package("zlib.out", "out"),
+            package("stdenv.cc.cc.lib", "out"),
+            package("openssl", "dev"),
+            package("zlib", "out"),
+        ];
+        assert_eq!(
+            dedup_packages(packages),
+            vec![
+                package("zlib", "out"),
+                package("stdenv.cc.cc", "lib"),
+                package("openssl", "dev"),
+            ]
+        );
+    }
+
+    fn lib(name: &str) -> Arc<MissingLib> {
+        Arc::new(MissingLib {
+            name: name.to_string(),
+        })
+    }
+
+    #[test]
+    fn candidates_summary_truncates_long_lines() {
+        let mut missing_map = HashMap::new();
+        missing_map.insert(
+            lib("libmany.so.1"),
+            (0..30)
+                .map(|i| package(&format!("package{:02}", i), "out"))
+                .collect(),
+        );
+        missing_map.insert(lib("libone.so.1"), vec![package("one", "lib")]);
+        missing_map.insert(lib("libnone.so.1"), Vec::new());
+
+        let summary = format_candidates_summary(&missing_map);
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines.len(), 3);
+
+        let many = lines[0];
+        assert!(many.starts_with("libmany.so.1 -> 30 candidates: package00.out package01.out"));
+        assert!(many.len() <= SUMMARY_WIDTH, "{} is too long", many);
+        let shown = many.matches("package").count();
+        assert!(many.ends_with(&format!(" ...and {} more", 30 - shown)));
+
+        assert_eq!(lines[1], "libnone.so.1 -> 0 candidates:");
+        assert_eq!(lines[2], "libone.so.1 -> 1 candidate: one.lib");
+    }
+
+    /// Checks that strings, interpolations and brackets of a nix expression
+    /// are terminated and balanced, as far as this is possible without nix
+    fn check_nix_syntax(expr: &str) -> Result<(), String> {
+        enum Context {
+            Expr(Option<char>),
+            Str,
+            IndentedStr,
+        }
+
+        let chars: Vec<char> = expr.chars().collect();
+        let at = |i: usize, s: &str| {
+            s.chars()
+                .enumerate()
+                .all(|(j, c)| chars.get(i + j) == Some(&c))
+        };
+        let mut stack = vec![Context::Expr(None)];
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            match stack.last().expect("the top level is never left") {
+                Context::Expr(close) => match c {
+                    '#' => {
+                        while i + 1 < chars.len() && chars[i + 1] != '\n' {
+                            i += 1;
+                        }
+                    }
+                    '"' => stack.push(Context::Str),
+                    '\'' if at(i, "''") => {
+                        stack.push(Context::IndentedStr);
+                        i += 1;
+                    }
+                    '{' => stack.push(Context::Expr(Some('}'))),
+                    '[' => stack.push(Context::Expr(Some(']'))),
+                    '(' => stack.push(Context::Expr(Some(')'))),
+                    '}' | ']' | ')' if *close == Some(c) => {
+                        stack.pop();
+                    }
+                    '}' | ']' | ')' => return Err(format!("unbalanced {} at {}", c, i)),
+                    _ => {}
+                },
+                Context::Str => match c {
+                    '\\' => i += 1,
+                    '"' => {
+                        stack.pop();
+                    }
+                    '$' if at(i, "${") => {
+                        stack.push(Context::Expr(Some('}')));
+                        i += 1;
+                    }
+                    _ => {}
+                },
+                Context::IndentedStr => {
+                    if at(i, "''\\") {
+                        i += 3;
+                    } else if at(i, "'''") || at(i, "''$") {
+                        i += 2;
+                    } else if at(i, "''") {
+                        stack.pop();
+                        i += 1;
+                    } else if at(i, "${") {
+                        stack.push(Context::Expr(Some('}')));
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+        }
+        match stack.len() {
+            1 => Ok(()),
+            _ => Err("unterminated string or bracket".to_string()),
+        }
+    }
+
+    #[test]
+    fn fhs_shell_names_the_fhs() {
+        let expr = fhs_shell(None, std::iter::empty(), &FhsOptions::default());
+        assert!(expr.contains("\n    name = \"fhs\";\n"));
+    }
+
+    #[test]
+    fn fhs_shell_escapes_the_binary_path() {
+        let options = FhsOptions {
+            argv0: Some("app".to_string()),
+            ..Default::default()
+        };
+        let expr = fhs_shell(
+            Some(Path::new("/opt/it's ${HOME}/app")),
+            std::iter::empty(),
+            &options,
+        );
+        // the `''` of the shell quote and the `${` are escaped in the indented
+        // string
+        assert!(expr.contains(r#"exec -a 'app' '/opt/it'\'''s ''${HOME}/app' "$@""#));
+        check_nix_syntax(&expr).unwrap();
+
+        let expr = fhs_shell(
+            Some(Path::new("/opt/\"quoted\" ${HOME}/app")),
+            std::iter::empty(),
+            &FhsOptions::default(),
+        );
+        assert!(expr.contains(r#"runScript = "/opt/\"quoted\" \${HOME}/app";"#));
+        check_nix_syntax(&expr).unwrap();
+    }
+
+    #[test]
+    fn fhs_shell_renders_no_packages_as_empty_list() {
+        let expr = fhs_shell(None, std::iter::empty(), &FhsOptions::default());
+        assert!(expr.contains("targetPkgs = p: with p; [ ];"));
+    }
+
+    #[test]
+    fn fhs_shell_puts_packages_on_separate_lines() {
+        let packages = [package("zlib", "out"), package("openssl", "dev")];
+        let expr = fhs_shell(
+            None,
+            packages.iter().map(|p| p.as_ref()),
+            &FhsOptions::default(),
+        );
+        assert!(expr.contains("targetPkgs = p: with p; [\n      openssl.dev\n      zlib\n    ];"));
+    }
+
+    #[test]
+    fn fhs_shell_starts_with_the_nixpkgs_import() {
+        let expr = fhs_shell(
+            Some(Path::new("/opt/app")),
+            std::iter::empty(),
+            &FhsOptions::default(),
+        );
+        assert!(expr.starts_with("with import <nixpkgs> {};\n"));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn fhs_shell_renders_valid_nix(
+            attrs in proptest::collection::vec(
+                r"[a-zA-Z_+][a-zA-Z0-9_+-]{0,8}(\.[a-zA-Z0-9_+-]{1,8}){0,2}",
+                0..6,
+            ),
+            binary in r#"/[a-z '"$\\{}]{1,16}"#,
+        ) {
+            let packages: Vec<_> = attrs.into_iter().map(Package::from_attr).collect();
+            let options = FhsOptions {
+                argv0: Some("app".to_string()),
+                ..Default::default()
+            };
+            let expr = fhs_shell(Some(Path::new(&binary)), packages.iter(), &options);
+            proptest::prop_assert!(check_nix_syntax(&expr).is_ok(), "{}", expr);
+            if command_output("nix-instantiate", &["--version"]).is_ok() {
+                proptest::prop_assert!(validate_nix_expression(&expr).is_ok(), "{}", expr);
+            }
+        }
+    }
+
+    /// A path in the temp dir unique to this test process
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nix-autobahn-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn nix_shell_wrapper_takes_the_shebang_and_execs_the_fhs() {
+        let options = ScriptOptions {
+            shebang: "#!/run/current-system/sw/bin/bash".to_string(),
+            ..Default::default()
+        };
+        let target = temp_path("shebang");
+        write_bash_script(
+            &target,
+            &options.shebang,
+            &options.exec_fhs("with import <nixpkgs> {}; hello"),
+        )
+        .unwrap();
+        let script = fs::read_to_string(&target).unwrap();
+        fs::remove_file(&target).unwrap();
+
+        assert_eq!(
+            script,
+            "#!/run/current-system/sw/bin/bash\n\n\
+            fhs=$(nix-build --no-out-link -E 'with import <nixpkgs> {}; hello') || exit; \
+            exec \"$fhs/bin/fhs\" \"$@\"\n"
+        );
+    }
+
+    #[test]
+    fn strip_soname_version_keeps_the_major_version() {
+        assert_eq!(strip_soname_version("libssl.so.1.1.1l"), "libssl.so.1");
+        assert_eq!(strip_soname_version("libfoo.so.2"), "libfoo.so.2");
+        assert_eq!(strip_soname_version("libbar.so"), "libbar.so");
+    }
+
+    #[test]
+    fn env_vars_are_exported_in_the_profile() {
+        let options = FhsOptions {
+            env: vec![
+                parse_env_var("APP_MODE=release").unwrap(),
+                parse_env_var("GREETING=it's ${HOME}").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let expr = fhs_shell(None, std::iter::empty(), &options);
+
+        // shell quoted for the profile script, then escaped for the nix string
+        assert!(expr.contains(
+            "profile = \"export APP_MODE='release'\nexport GREETING='it'\\\\''s \\${HOME}'\n\";"
+        ));
+        check_nix_syntax(&expr).unwrap();
+        assert!(parse_env_var("1ST=x").is_err());
+        assert!(parse_env_var("NO_VALUE").is_err());
+    }
+
+    #[test]
+    fn parse_ldd_output_reads_a_captured_file() {
+        let libs = parse_ldd_output(include_str!("../tests/fixtures/ldd-output.txt"));
+        let names: Vec<_> = libs.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["libsteam_api.so", "libSDL2-2.0.so.0", "libGL.so.1"]);
+    }
+
+    #[test]
+    fn separately_allocated_packages_are_deduplicated() {
+        let a = package("openssl", "out");
+        let b = package("openssl", "out");
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!(a, b);
+
+        let deduped = dedup_packages(vec![a, package("openssl", "dev"), b]);
+        assert_eq!(
+            deduped,
+            [package("openssl", "out"), package("openssl", "dev")]
+        );
+    }
+
+    #[test]
+    fn is_elf_checks_the_magic_bytes() {
+        let elf = temp_path("elf");
+        fs::write(&elf, b"\x7fELF\x02\x01\x01\x00\x00\x00").unwrap();
+        let script = temp_path("script");
+        fs::write(&script, "#!/bin/sh\necho hello\n").unwrap();
+        let short = temp_path("short");
+        fs::write(&short, b"\x7fEL").unwrap();
+
+        assert!(is_elf(&elf));
+        assert!(!is_elf(&script));
+        assert!(!is_elf(&short));
+        assert!(!is_elf(&temp_path("missing")));
+
+        for file in [elf, script, short] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    fn scorer() -> PackageScorer {
+        PackageScorer {
+            prefer: Vec::new(),
+            exclude: Vec::new(),
+            prefer_output: "out".to_string(),
+        }
+    }
+
+    #[test]
+    fn name_match_prefers_the_attribute_named_like_the_lib() {
+        let similarity = |attr| name_similarity("libpng.so.16", attr);
+        assert_eq!(similarity("libpng"), 1.0);
+        assert!(similarity("libpng") > similarity("libpng12"));
+        assert!(similarity("libpng12") > similarity("imagemagick"));
+        assert!(similarity("xorg.libpng") > similarity("gimp"));
+
+        let candidates = [
+            package("imagemagick", "out"),
+            package("gimp", "out"),
+            package("libpng", "out"),
+            package("libpng12", "out"),
+        ];
+        assert_eq!(
+            scorer().best_by_name(&lib("libpng.so.16"), &candidates),
+            Some(&package("libpng", "out"))
+        );
+    }
+
+    #[test]
+    fn nix_index_entries_keep_attribute_and_output() {
+        let p = Package::from_nix_index_entry("python3Packages.numpy", "out");
+        assert_eq!(p.attr, "python3Packages.numpy");
+        assert_eq!(p.output, "out");
+        assert_eq!(p.to_string(), "python3Packages.numpy.out");
+        assert_eq!(p.to_nix_attr(), "python3Packages.numpy");
+
+        // unlike user given attributes, nothing is split off
+        let p = Package::from_nix_index_entry("stdenv.cc.cc", "lib");
+        assert_eq!(p.to_string(), "stdenv.cc.cc.lib");
+        assert_eq!(p, Package::from_attr("stdenv.cc.cc.lib".to_string()));
+    }
+
+    #[test]
+    fn parse_ldd_line_only_reads_missing_libs() {
+        let name = |line| parse_ldd_line(line).map(|l| l.name);
+        assert_eq!(name("\tlinux-vdso.so.1 (0x00007ffd5a1f2000)"), None);
+        assert_eq!(name("\tlinux-gate.so.1 => (0xf7f1b000)"), None);
+        assert_eq!(
+            name("\t/lib64/ld-linux-x86-64.so.2 (0x00007f3c1a4f6000)"),
+            None
+        );
+        assert_eq!(name("\tstatically linked"), None);
+        assert_eq!(name("\tnot a dynamic executable"), None);
+        assert_eq!(
+            name("\tlibc.so.6 => /usr/lib/libc.so.6 (0x00007f3c1a200000)"),
+            None
+        );
+        assert_eq!(
+            name("\tlibfoo.so.1 => not found"),
+            Some("libfoo.so.1".to_string())
+        );
+        assert_eq!(
+            name(
+                "Error loading shared library libbar.so.2: No such file or directory \
+                (needed by /opt/app)"
+            ),
+            Some("libbar.so.2".to_string())
+        );
+        assert_eq!(name("\t/opt/lib/libbaz.so => not found"), None);
+    }
+
+    fn origin(attr: &str, output: &str, toplevel: bool, system: Option<&str>) -> PathOrigin {
+        PathOrigin {
+            attr: attr.to_string(),
+            output: output.to_string(),
+            toplevel,
+            system: system.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn only_toplevel_origins_of_the_system_are_packages() {
+        let db = Database {
+            path: PathBuf::new(),
+            system: Some("x86_64-linux".to_string()),
+            open_timeout: Duration::from_secs(1),
+            prefer_regular_files: false,
+            prefix_match: false,
+        };
+        assert_eq!(
+            db.package_of(&origin("python3Packages.numpy", "out", true, None)),
+            Some(Package::from_nix_index_entry(
+                "python3Packages.numpy",
+                "out"
+            ))
+        );
+        assert_eq!(
+            db.package_of(&origin("openssl", "dev", true, Some("x86_64-linux"))),
+            Some(Package::from_nix_index_entry("openssl", "dev"))
+        );
+        // a dependency found in the closure of firefox, not firefox itself
+        assert_eq!(db.package_of(&origin("firefox", "out", false, None)), None);
+        assert_eq!(
+            db.package_of(&origin("openssl", "out", true, Some("aarch64-linux"))),
+            None
+        );
+    }
+
+    #[test]
+    fn attr_paths_quote_invalid_identifiers() {
+        assert_eq!(package("gtk+3", "out").to_nix_attr(), r#""gtk+3""#);
+        assert_eq!(package("gtk+3", "dev").to_string(), r#""gtk+3".dev"#);
+        assert_eq!(
+            package("haskellPackages.gtk2hs-buildtools", "out").to_nix_attr(),
+            "haskellPackages.gtk2hs-buildtools"
+        );
+        assert_eq!(
+            package("nodePackages.@angular/cli", "out").to_nix_attr(),
+            r#"nodePackages."@angular/cli""#
+        );
+    }
+
+    #[test]
+    fn annotations_follow_their_package() {
+        let packages = [package("zlib", "out"), package("openssl", "out")];
+        let mut options = FhsOptions::default();
+        options
+            .annotations
+            .insert(Package::clone(&packages[0]), "for libz.so.1".to_string());
+        options.annotations.insert(
+            Package::clone(&packages[1]),
+            "for libcrypto.so.3 libssl.so.3".to_string(),
+        );
+        let expr = fhs_shell(None, packages.iter().map(|p| p.as_ref()), &options);
+        assert!(expr.contains(
+            "[\n      openssl # for libcrypto.so.3 libssl.so.3\n      zlib # for libz.so.1\n    ];"
+        ));
+    }
+
+    #[test]
+    fn nix_attrs_leave_out_the_default_output() {
+        assert_eq!(package("zlib", "out").to_nix_attr(), "zlib");
+        assert_eq!(package("openssl", "dev").to_nix_attr(), "openssl.dev");
+    }
+
+    #[test]
+    fn pkgs_cross_prefixes_the_package_set_once() {
+        let packages = [package("zlib", "out"), package("openssl", "dev")];
+        let options = FhsOptions {
+            pkgs_cross: Some("aarch64-multiplatform".to_string()),
+            ..Default::default()
+        };
+        let expr = fhs_shell(None, packages.iter().map(|p| p.as_ref()), &options);
+        assert_eq!(expr.matches("pkgsCross").count(), 1);
+        assert!(expr.contains(
+            "targetPkgs = p: with p.pkgsCross.aarch64-multiplatform; [\n      openssl.dev\n      zlib\n    ];"
+        ));
+        assert!(expr.starts_with("# a bare FHS"));
+        assert!(expr.contains("with import <nixpkgs> {};\n"));
+    }
+
+    #[test]
+    fn overlays_are_passed_to_the_nixpkgs_import() {
+        let options = FhsOptions {
+            system: Some("aarch64-linux".to_string()),
+            overlays: vec![
+                PathBuf::from("/etc/nixos/overlays/games.nix"),
+                PathBuf::from("/home/me/my overlay.nix"),
+            ],
+            ..Default::default()
+        };
+        let expr = fhs_shell(Some(Path::new("/opt/app")), std::iter::empty(), &options);
+        assert!(expr.starts_with(
+            "with import <nixpkgs> { system = \"aarch64-linux\"; overlays = [ \
+            (import (/. + \"/etc/nixos/overlays/games.nix\")) \
+            (import (/. + \"/home/me/my overlay.nix\")) ]; };\n  buildFHSUserEnv {"
+        ));
+    }
+
+    #[test]
+    fn fhs_shell_renders_borrowed_packages_unchanged() {
+        let packages = [package("zlib", "out"), package("openssl", "dev")];
+        let options = FhsOptions {
+            binds: vec![PathBuf::from("/opt")],
+            ..Default::default()
+        };
+        let expected = r#"with import <nixpkgs> {};
+  buildFHSUserEnv {
+    name = "fhs";
+    targetPkgs = p: with p; [
+      openssl.dev
+      zlib
+    ];
+    extraBwrapArgs = [
+      "--ro-bind" "/opt" "/opt"
+    ];
+    profile = "";
+    extraBuildCommands = "";
+    runScript = "/opt/app";
+  }"#;
+        let borrowed = fhs_shell(
+            Some(Path::new("/opt/app")),
+            packages.iter().map(|p| p.as_ref()),
+            &options,
+        );
+        assert_eq!(borrowed, expected);
+
+        let owned: Vec<Package> = packages.iter().map(|p| Package::clone(p)).collect();
+        let from_slice = fhs_shell(Some(Path::new("/opt/app")), owned.iter(), &options);
+        assert_eq!(from_slice, expected);
+    }
+
+    #[test]
+    fn preferred_output_wins_among_outputs() {
+        let candidates = [package("openssl", "dev"), package("openssl", "out")];
+        assert_eq!(scorer().best(&candidates), Some(&package("openssl", "out")));
+
+        let prefer_dev = PackageScorer {
+            prefer_output: "dev".to_string(),
+            ..scorer()
+        };
+        assert_eq!(
+            prefer_dev.best(&candidates),
+            Some(&package("openssl", "dev"))
+        );
+
+        // a soft preference, other outputs are still taken
+        let candidates = [package("openssl", "dev"), package("openssl", "bin")];
+        assert_eq!(scorer().best(&candidates), Some(&package("openssl", "bin")));
+    }
+
+    #[test]
+    fn derivation_links_the_fhs_as_the_binary() {
+        let target = temp_path("derivation.nix");
+        write_derivation_nix(&target, Path::new("/opt/my app/game"), "FHS").unwrap();
+        let expression = fs::read_to_string(&target).unwrap();
+        fs::remove_file(&target).unwrap();
+
+        assert_eq!(
+            expression,
+            r#"with import <nixpkgs> {};
+let
+  fhs = FHS;
+in
+stdenv.mkDerivation rec {
+  name = "game";
+  dontUnpack = true;
+  installPhase = ''
+    mkdir -p $out/bin
+    ln -s ${fhs}/bin/fhs "$out/bin/${name}"
+  '';
+}
+"#
+        );
+    }
+
+    #[test]
+    fn chdir_prefixes_the_run_script() {
+        let options = FhsOptions {
+            chdir: Some(PathBuf::from("/opt/it's here")),
+            ..Default::default()
+        };
+        let expr = fhs_shell(
+            Some(Path::new("/opt/it's here/app")),
+            std::iter::empty(),
+            &options,
+        );
+        assert!(expr.contains(
+            "runScript = writeShellScript \"run\" ''\n      \
+            cd '/opt/it'\\'''s here' && exec '/opt/it'\\'''s here/app' \"$@\"\n    '';"
+        ));
+        check_nix_syntax(&expr).unwrap();
+    }
+
+    #[test]
+    fn regular_files_are_preferred_over_symlinks() {
+        let hit = |attr: &str, regular| {
+            (
+                Package::from_nix_index_entry(attr, "out"),
+                format!("/lib/libfoo.so.1 of {}", attr).into_bytes(),
+                regular,
+            )
+        };
+        let attrs = |packages: Vec<Package>| -> Vec<String> {
+            packages.into_iter().map(|p| p.attr).collect()
+        };
+        let hits = vec![hit("foo-compat", false), hit("foo", true)];
+
+        assert_eq!(attrs(providing_packages(hits.clone(), true)), ["foo"]);
+        assert_eq!(
+            attrs(providing_packages(hits, false)),
+            ["foo-compat", "foo"]
+        );
+        // symlinks are taken if nothing provides a regular file
+        assert_eq!(
+            attrs(providing_packages(vec![hit("foo-compat", false)], true)),
+            ["foo-compat"]
+        );
+    }
+
+    #[test]
+    fn soname_regex_matches_the_soname_literally() {
+        let regex = soname_regex("libstdc++.so.6", false).unwrap();
+        assert!(regex.is_match(b"/lib/libstdc++.so.6"));
+        // `+` and `.` are no quantifier and wildcard
+        assert!(!regex.is_match(b"/lib/libstdcc.so.6"));
+        assert!(!regex.is_match(b"/lib/libstdc++xso.6"));
+        // anchored at both ends
+        assert!(!regex.is_match(b"/lib/libstdc++.so.6.0.30"));
+        assert!(!regex.is_match(b"/lib/xlibstdc++.so.6"));
+
+        let regex = soname_regex("libstdc++.so.6", true).unwrap();
+        assert!(regex.is_match(b"/lib/libstdc++.so.6.0.30"));
+        assert!(!regex.is_match(b"/lib/libstdcc.so.6.0.30"));
+    }
+
+    #[test]
+    fn libs_are_dropped_only_if_the_excluded_package_is_their_only_candidate() {
+        let mut missing_map = HashMap::new();
+        missing_map.insert(lib("libfoo.so.1"), vec![package("foo", "out")]);
+        // both outputs are the same package
+        missing_map.insert(
+            lib("libfoo-extra.so.1"),
+            vec![package("foo", "out"), package("foo", "lib")],
+        );
+        missing_map.insert(
+            lib("libbar.so.2"),
+            vec![package("foo", "out"), package("bar", "out")],
+        );
+        missing_map.insert(lib("libbaz.so.3"), vec![]);
+
+        let dropped = drop_libs_provided_only_by(&mut missing_map, &["foo".to_string()]);
+        assert_eq!(dropped, [lib("libfoo-extra.so.1"), lib("libfoo.so.1")]);
+        // bar can still provide libbar.so.2, unresolved libs are kept as well
+        let mut kept: Vec<_> = missing_map.keys().map(|l| l.name.as_str()).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, ["libbar.so.2", "libbaz.so.3"]);
+    }
+
+    #[test]
+    fn fhs_shell_output_is_independent_of_the_package_order() {
+        let packages = [
+            package("zlib", "out"),
+            package("openssl", "dev"),
+            package("alsa-lib", "out"),
+            package("xorg.libX11", "out"),
+        ];
+        let expected = r#"with import <nixpkgs> {};
+  buildFHSUserEnv {
+    name = "fhs";
+    targetPkgs = p: with p; [
+      alsa-lib
+      openssl.dev
+      xorg.libX11
+      zlib
+    ];
+    extraBwrapArgs = [
+      "--ro-bind" "/opt" "/opt"
+    ];
+    profile = "";
+    extraBuildCommands = "";
+    runScript = "/opt/app";
+  }"#;
+        let options = FhsOptions {
+            binds: vec![PathBuf::from("/opt")],
+            ..Default::default()
+        };
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1], [1, 3, 0, 2]] {
+            let shuffled = order.iter().map(|&i| packages[i].as_ref());
+            let expr = fhs_shell(Some(Path::new("/opt/app")), shuffled, &options);
+            assert_eq!(expr, expected);
+        }
+    }
+
+    #[test]
+    fn packages_covering_a_strict_subset_are_dominated() {
+        let mut candidates_map = HashMap::new();
+        candidates_map.insert(
+            package("big", "out"),
+            vec![lib("lib1.so"), lib("lib2.so"), lib("lib3.so")],
+        );
+        candidates_map.insert(package("small", "out"), vec![lib("lib1.so")]);
+        // covers lib4.so, which nothing else does
+        candidates_map.insert(
+            package("other", "out"),
+            vec![lib("lib2.so"), lib("lib4.so")],
+        );
+        // of two equal sets, neither dominates the other
+        candidates_map.insert(package("twin", "out"), vec![lib("lib2.so"), lib("lib4.so")]);
+
+        let mut kept: Vec<_> = remove_dominated_packages(&candidates_map)
+            .into_iter()
+            .map(|p| p.attr.clone())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["big", "other", "twin"]);
+    }
+
+    #[test]
+    fn fhs_shell_sets_argv0_with_exec_a() {
+        let options = FhsOptions {
+            argv0: Some("my app".to_string()),
+            ..Default::default()
+        };
+        let expr = fhs_shell(Some(Path::new("/opt/app")), std::iter::empty(), &options);
+        assert!(expr.contains(
+            "runScript = writeShellScript \"run\" ''\n      exec -a 'my app' '/opt/app' \"$@\"\n    '';"
+        ));
+
+        // neither a bare FHS nor a custom run script gets an argv[0]
+        let expr = fhs_shell(None, std::iter::empty(), &options);
+        assert!(expr.contains("runScript = \"bash\";"));
+        let options = FhsOptions {
+            run_script: Some("my-app --flag".to_string()),
+            ..options
+        };
+        let expr = fhs_shell(Some(Path::new("/opt/app")), std::iter::empty(), &options);
+        assert!(expr.contains("runScript = \"my-app --flag\";"));
+    }
+
+    fn script_options(shell: ShellType) -> ScriptOptions {
+        ScriptOptions {
+            shebang: shebang_for_shell(shell).to_string(),
+            shell,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bash_wrapper_uses_bash() {
+        assert_eq!(shebang_for_shell(ShellType::Bash), "#!/usr/bin/env bash");
+        assert_eq!(
+            script_options(ShellType::Bash).exec_fhs("it's"),
+            r#"fhs=$(nix-build --no-out-link -E 'it'\''s') || exit; exec "$fhs/bin/fhs" "$@""#
+        );
+    }
+
+    #[test]
+    fn zsh_wrapper_uses_zsh() {
+        assert_eq!(shebang_for_shell(ShellType::Zsh), "#!/usr/bin/env zsh");
+        assert_eq!(ShellType::Zsh.quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn sh_wrapper_uses_sh() {
+        assert_eq!(shebang_for_shell(ShellType::Sh), "#!/bin/sh");
+        assert_eq!(ShellType::Sh.quote(r"a\b"), r"'a\b'");
+    }
+
+    #[test]
+    fn fish_wrapper_uses_fish() {
+        assert_eq!(shebang_for_shell(ShellType::Fish), "#!/usr/bin/env fish");
+        // fish reads backslash escapes even within single quotes
+        assert_eq!(ShellType::Fish.quote(r"it's a\b"), r"'it\'s a\\b'");
+        assert_eq!(
+            script_options(ShellType::Fish).exec_fhs("{}"),
+            "set fhs (nix-build --no-out-link -E '{}'); or exit 1; exec $fhs/bin/fhs $argv"
+        );
+    }
+
+    /// Runs the nix-shell wrapper of a shell with a nix-build that times out,
+    /// passing a command creating a file as the arguments. Returns the exit
+    /// code of the wrapper and whether the file got created, or `None` if the
+    /// shell is not available.
+    fn run_wrapper_with_failing_build(
+        shell: ShellType,
+        program: &str,
+    ) -> Option<(Option<i32>, bool)> {
+        command_output(program, &["-c", "true"]).ok()?;
+        let dir = temp_path(&format!("failing-build-{}", program));
+        fs::create_dir_all(&dir).unwrap();
+        let nix_build = dir.join("nix-build");
+        // the exit code of timeout when the command timed out
+        write_bash_script(&nix_build, "#!/bin/sh", "exit 124").unwrap();
+        let script = dir.join("run-with-nix");
+        let options = script_options(shell);
+        write_bash_script(&script, &options.shebang, &options.exec_fhs("{}")).unwrap();
+
+        let marker = dir.join("marker");
+        let path = std::env::join_paths(std::iter::once(dir.clone()).chain(std::env::split_paths(
+            &std::env::var_os("PATH").unwrap_or_default(),
+        )))
+        .unwrap();
+        let status = Command::new(program)
+            .arg(&script)
+            .arg("touch")
+            .arg(&marker)
+            .env("PATH", path)
+            .status()
+            .unwrap();
+        let ran_args = marker.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        Some((status.code(), ran_args))
+    }
+
+    #[test]
+    fn wrapper_fails_if_the_build_fails() {
+        for (shell, program) in [
+            (ShellType::Bash, "bash"),
+            (ShellType::Zsh, "zsh"),
+            (ShellType::Sh, "sh"),
+        ] {
+            if let Some((code, ran_args)) = run_wrapper_with_failing_build(shell, program) {
+                assert_eq!(code, Some(124), "{}", program);
+                assert!(!ran_args, "{} ran the arguments as a command", program);
+            }
+        }
+    }
+
+    #[test]
+    fn fish_wrapper_fails_if_the_build_fails() {
+        if let Some((code, ran_args)) = run_wrapper_with_failing_build(ShellType::Fish, "fish") {
+            assert_eq!(code, Some(1));
+            assert!(!ran_args, "the arguments were run as a command");
+        }
+    }
+
+    /// The value of a nix string literal, as far as the escapes of
+    /// `nix_string_escape` and `nix_indented_string_escape` go, or `None` if it
+    /// contains an antiquotation
+    fn nix_string_value(literal: &str) -> Option<String> {
+        let (indented, body) = match literal.strip_prefix("''") {
+            Some(body) => (true, body.strip_suffix("''")?),
+            None => (false, literal.strip_prefix('"')?.strip_suffix('"')?),
+        };
+        let mut value = String::new();
+        let mut rest = body;
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("${") {
+                return None;
+            }
+            let (escaped, len) = match (indented, c) {
+                (false, '\\') => {
+                    let escaped = rest[1..].chars().next()?;
+                    (escaped.to_string(), 1 + escaped.len_utf8())
+                }
+                (true, '\'') if rest.starts_with("'''") => ("''".to_string(), 3),
+                (true, '\'') if rest.starts_with("''$") => ("$".to_string(), 3),
+                (true, '\'') if rest.starts_with("''") => return None,
+                _ => (c.to_string(), c.len_utf8()),
+            };
+            value += &escaped;
+            rest = &rest[len..];
+        }
+        Some(value)
+    }
+
+    #[test]
+    fn nix_string_escape_escapes_quotes_backslashes_and_antiquotations() {
+        assert_eq!(
+            nix_string_escape(r#"a "b" \c ${d} $e"#),
+            r#"a \"b\" \\c \${d} $e"#
+        );
+        assert_eq!(
+            nix_indented_string_escape("a ''b'' ${c} $d"),
+            "a '''b''' ''${c} $d"
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn nix_string_escape_round_trips(s in r#"[a-z '"$\\{}]{0,16}"#) {
+            let literal = format!("\"{}\"", nix_string_escape(&s));
+            proptest::prop_assert_eq!(nix_string_value(&literal), Some(s.clone()));
+            if command_output("nix-instantiate", &["--version"]).is_ok() {
+                let value = pipe_through("nix-instantiate", &["--eval", "--json", "-"], &literal)
+                    .unwrap();
+                proptest::prop_assert_eq!(value.trim(), serde_json::to_string(&s).unwrap());
+            }
+        }
+
+        #[test]
+        fn fhs_shell_escapes_the_run_script_of_write_shell_script(
+            binary in r#"/[a-z '"$\\{}]{1,16}"#,
+        ) {
+            let options = FhsOptions {
+                argv0: Some("app".to_string()),
+                ..Default::default()
+            };
+            let expr = fhs_shell(Some(Path::new(&binary)), std::iter::empty(), &options);
+            let literal = expr
+                .split("writeShellScript \"run\" ")
+                .nth(1)
+                .and_then(|s| s.strip_suffix(";\n  }"))
+                .unwrap();
+            proptest::prop_assert_eq!(
+                nix_string_value(literal),
+                Some(format!("\n      exec -a 'app' {} \"$@\"\n    ", shell_quote(&binary)))
+            );
+        }
+    }
+
+    #[test]
+    fn uncompilable_query_names_the_lib() {
+        let db = Database {
+            path: PathBuf::new(),
+            system: None,
+            open_timeout: Duration::from_secs(1),
+            prefer_regular_files: false,
+            prefix_match: false,
+        };
+        // even escaped, a name this long exceeds the size limit of a regex
+        let name = format!("lib{}.so.1", "x".repeat(1 << 20));
+        let e = MissingLib { name: name.clone() }
+            .find_candidates(&db)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("invalid database query for {}", name)
+        );
+    }
+
+    #[test]
+    fn injected_packages_take_the_indentation_of_the_sentinel_line() {
+        let file = temp_path("inject-indent");
+        fs::write(
+            &file,
+            "{\n  buildInputs = [ # nix-autobahn: start\n  # nix-autobahn: end\n  ];\n}\n",
+        )
+        .unwrap();
+        inject_packages(&file, &[Package::from_attr("zlib".to_string())]).unwrap();
+        let content = fs::read_to_string(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(
+            content,
+            "{\n  buildInputs = [ # nix-autobahn: start\n  zlib\n  # nix-autobahn: end\n  ];\n}\n"
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Runs the whole pipeline on a fixture binary, with ldd stubbed through
+//! `NIX_AUTOBAHN_LDD` and the lookup served by a mapping file
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+const LDD_STUB: &str = r#"#!/bin/sh
+printf '\tlinux-vdso.so.1 (0x00007ffd5a1f2000)\n'
+printf '\tlibfoo.so.1 => not found\n'
+printf '\tlibbar.so.2 => not found\n'
+printf '\tlibc.so.6 => /usr/lib/libc.so.6 (0x00007f3c1a200000)\n'
+"#;
+
+/// Maps both libs the ldd stub reports missing
+const MAPPING: &str = "\"libfoo.so.1\" = \"foo\"\n\"libbar.so.2\" = \"bar\"\n";
+
+/// Maps only libfoo.so.1, leaving libbar.so.2 to the database
+const PARTIAL_MAPPING: &str = "\"libfoo.so.1\" = \"foo\"\n";
+
+/// A scratch directory holding a fixture binary and the ldd stub
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("nix-autobahn-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app"), b"\x7fELF\x02\x01\x01\x00").unwrap();
+        let ldd = dir.join("ldd");
+        fs::write(&ldd, LDD_STUB).unwrap();
+        fs::set_permissions(&ldd, fs::Permissions::from_mode(0o755)).unwrap();
+        Self { dir }
+    }
+
+    fn write_mapping(&self, mapping: &str) -> PathBuf {
+        let path = self.dir.join("mapping.toml");
+        fs::write(&path, mapping).unwrap();
+        path
+    }
+
+    /// Runs nix-autobahn on the fixture binary, offline and without any
+    /// config file
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_on(&self.dir.join("app"), args)
+    }
+
+    /// Like [`Fixture::run`], asserting that the run succeeds
+    fn run_ok(&self, args: &[&str]) -> Output {
+        let output = self.run(args);
+        assert_success(&output);
+        output
+    }
+
+    fn run_on(&self, binary: &Path, args: &[&str]) -> Output {
+        self.command(binary)
+            .arg("--offline")
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs nix-autobahn on the fixture binary with the given database,
+    /// which it queries unless `--offline` is among the args
+    fn run_with_db(&self, db: &Path, args: &[&str]) -> Output {
+        self.command(&self.dir.join("app"))
+            .args(args)
+            .arg("--db")
+            .arg(db)
+            .output()
+            .unwrap()
+    }
+
+    /// nix-autobahn examining a binary, without any config file
+    fn command(&self, binary: &Path) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_nix-autobahn"));
+        command
+            .arg(binary)
+            .args(["--no-validate", "--progress-format", "none"])
+            .current_dir(&self.dir)
+            .env("NIX_AUTOBAHN_LDD", self.dir.join("ldd"))
+            .env("XDG_CONFIG_HOME", &self.dir);
+        command
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// The FHS expression of the written wrapper, which passes it to
+    /// nix-build as a single quoted word
+    fn expression(&self) -> String {
+        fs::read_to_string(self.path("run-with-nix"))
+            .unwrap()
+            .replace(r"'\''", "'")
+    }
+
+    /// The packages listed in the `targetPkgs` of the written wrapper
+    fn target_pkgs(&self) -> Vec<String> {
+        let expression = self.expression();
+        let list = expression
+            .split("targetPkgs = p: with p; [")
+            .nth(1)
+            .expect("the wrapper has targetPkgs");
+        list[..list.find(']').unwrap()]
+            .split_whitespace()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn mapped_libs_end_up_in_target_pkgs() {
+    let fixture = Fixture::new("mapped");
+    let mapping =
+        fixture.write_mapping("\"libfoo.so.1\" = \"foo\"\n\"libbar.so.2\" = \"bar.lib\"\n");
+
+    fixture.run_ok(&["--mapping-file", mapping.to_str().unwrap()]);
+    // libc.so.6 belongs to the FHS base and the vdso is no file at all
+    assert_eq!(fixture.target_pkgs(), ["bar.lib", "foo"]);
+}
+
+#[test]
+fn unresolved_libs_fail_with_strict() {
+    let fixture = Fixture::new("strict");
+    let mapping = fixture.write_mapping(PARTIAL_MAPPING);
+
+    let output = fixture.run(&["--mapping-file", mapping.to_str().unwrap(), "--strict"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(!fixture.path("run-with-nix").exists());
+}
+
+#[test]
+fn nix_shell_compat_runs_the_canonical_binary() {
+    let fixture = Fixture::new("compat");
+    let mapping = fixture.write_mapping(MAPPING);
+    std::os::unix::fs::symlink("app", fixture.path("link")).unwrap();
+
+    let output = fixture.run_on(
+        Path::new("link"),
+        &[
+            "--mapping-file",
+            mapping.to_str().unwrap(),
+            "--nix-shell-compat",
+        ],
+    );
+    assert_success(&output);
+
+    let shell_nix = fs::read_to_string(fixture.path("shell.nix")).unwrap();
+    let binary = fixture.path("app").canonicalize().unwrap();
+    assert!(shell_nix.contains(&format!(
+        "    (writeShellScriptBin \"run-binary\" ''\n      exec '{}' \"$@\"\n    '')\n",
+        binary.display()
+    )));
+    // entering the shell runs nothing but the exports
+    assert!(!shell_nix
+        .split("shellHook")
+        .nth(1)
+        .unwrap()
+        .contains("exec"));
+}
+
+#[test]
+fn mapped_libs_are_not_looked_up_in_the_database() {
+    let fixture = Fixture::new("precedence");
+    // the database does not exist, so any query would fail
+    let db = fixture.path("no-such-db");
+
+    let mapping = fixture.write_mapping(MAPPING);
+    let output = fixture.run_with_db(&db, &["--mapping-file", mapping.to_str().unwrap()]);
+    assert_success(&output);
+    assert_eq!(fixture.target_pkgs(), ["bar", "foo"]);
+
+    // a lib missing from the mapping is looked up, and thus fails
+    let mapping = fixture.write_mapping(PARTIAL_MAPPING);
+    let output = fixture.run_with_db(&db, &["--mapping-file", mapping.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn fhs_base_libs_are_skipped_by_default() {
+    let fixture = Fixture::new("base");
+    let mapping = fixture.write_mapping(MAPPING);
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--lib",
+        "libc.so.6",
+        "--strict",
+    ];
+
+    fixture.run_ok(&args);
+    assert_eq!(fixture.target_pkgs(), ["bar", "foo"]);
+
+    // offline, nothing provides libc.so.6 once it is not skipped
+    let output = fixture.run(&[&args[..], &["--no-skip-base"]].concat());
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn run_script_is_wrapped_in_a_shell_only_on_request() {
+    let fixture = Fixture::new("run-script-shell");
+    let mapping = fixture.write_mapping(MAPPING);
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--run-script",
+        "echo it's $0",
+    ];
+
+    fixture.run_ok(&args);
+    let expr = fixture.expression();
+    assert!(expr.contains("runScript = \"echo it's $0\";"), "{}", expr);
+
+    fixture.run_ok(&[&args[..], &["--run-script-shell", "bash"]].concat());
+    let expr = fixture.expression();
+    assert!(
+        expr.contains(r#"runScript = "'bash' -c 'echo it'\\''s $0' fhs";"#),
+        "{}",
+        expr
+    );
+}
+
+#[test]
+fn libs_only_provided_by_an_excluded_package_are_dropped() {
+    let fixture = Fixture::new("exclude-via");
+    let mapping = fixture.write_mapping(MAPPING);
+
+    let output = fixture.run_ok(&[
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--exclude-lib-via-package",
+        "bar",
+        "--strict",
+    ]);
+    // libbar.so.2 is dropped instead of left unresolved, so --strict passes
+    assert_eq!(fixture.target_pkgs(), ["foo"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("dropping libbar.so.2"));
+}
+
+#[test]
+fn argv0_defaults_to_the_basename_of_the_binary() {
+    let fixture = Fixture::new("argv0");
+    let mapping = fixture.write_mapping(MAPPING);
+    let binary = fixture.path("app").canonicalize().unwrap();
+    let exec = |argv0: &str| format!("exec -a '{}' '{}' \"$@\"", argv0, binary.display());
+
+    fixture.run_ok(&["--mapping-file", mapping.to_str().unwrap()]);
+    let expr = fixture.expression();
+    assert!(expr.contains(&exec("app")), "{}", expr);
+
+    fixture.run_ok(&[
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--argv0",
+        "launcher",
+    ]);
+    let expr = fixture.expression();
+    assert!(expr.contains(&exec("launcher")), "{}", expr);
+}
+
+#[test]
+fn invalid_raw_regex_is_a_usage_error() {
+    let fixture = Fixture::new("raw-regex");
+
+    // the pattern is compiled before any lib is looked up in the missing
+    // database, which would fail with exit code 5
+    let output = fixture.run_with_db(
+        &fixture.path("no-such-db"),
+        &["--raw-regex", "--lib", "libfoo(.so"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("invalid --raw-regex pattern libfoo(.so"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn lockfile_is_skipped_without_a_database() {
+    let fixture = Fixture::new("lock-no-db");
+    let mapping = fixture.write_mapping(MAPPING);
+    let lockfile = fixture.path("lock.toml");
+
+    let output = fixture.run_with_db(
+        &fixture.path("no-such-db"),
+        &[
+            "--mapping-file",
+            mapping.to_str().unwrap(),
+            "--offline",
+            "--lockfile",
+            lockfile.to_str().unwrap(),
+        ],
+    );
+    assert_success(&output);
+    assert!(!lockfile.exists());
+}
+
+#[test]
+fn lockfile_records_only_successful_lookups() {
+    let fixture = Fixture::new("lock-offline");
+    let mapping = fixture.write_mapping(PARTIAL_MAPPING);
+    let lockfile = fixture.path("lock.toml");
+    // any file does for fingerprinting the database
+    let db = fixture.path("db");
+    fs::create_dir(&db).unwrap();
+    fs::write(db.join("files"), b"").unwrap();
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--lockfile",
+        lockfile.to_str().unwrap(),
+    ];
+
+    let output = fixture.run_with_db(&db, &[&args[..], &["--offline"]].concat());
+    assert_eq!(output.status.code(), Some(4));
+    // libbar.so.2 stays unresolved offline, libfoo.so.1 is mapped
+    let locked = fs::read_to_string(&lockfile).unwrap();
+    assert!(!locked.contains("libbar.so.2"), "{}", locked);
+    assert!(!locked.contains("libfoo.so.1"), "{}", locked);
+
+    // the empty database fails every query, which --keep-going only warns about
+    let output = fixture.run_with_db(&db, &[&args[..], &["--keep-going"]].concat());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unable to look up libbar.so.2"),
+        "{}",
+        stderr
+    );
+    let locked = fs::read_to_string(&lockfile).unwrap();
+    assert!(!locked.contains("libbar.so.2"), "{}", locked);
+}
+
+#[test]
+fn wrap_in_place_never_moves_a_wrapper_onto_the_original() {
+    let fixture = Fixture::new("wrap-in-place");
+    let mapping = fixture.write_mapping(MAPPING);
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--wrap-in-place",
+    ];
+    let original = fs::read(fixture.path("app")).unwrap();
+
+    fixture.run_ok(&args);
+    assert_eq!(fs::read(fixture.path("app.orig")).unwrap(), original);
+    let wrapper = fs::read(fixture.path("app")).unwrap();
+    assert!(wrapper.starts_with(b"#!"));
+
+    // wrapping the wrapper again is refused, even with --force
+    let output = fixture.run(&[&args[..], &["--force"]].concat());
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("wrapped already"));
+    assert_eq!(fs::read(fixture.path("app.orig")).unwrap(), original);
+    assert_eq!(fs::read(fixture.path("app")).unwrap(), wrapper);
+
+    // a new binary replaces a stale original only with --force
+    fs::write(fixture.path("app"), &original).unwrap();
+    fs::write(fixture.path("app.orig"), b"stale").unwrap();
+    let output = fixture.run(&args);
+    assert!(!output.status.success());
+    assert_eq!(fs::read(fixture.path("app.orig")).unwrap(), b"stale");
+    fixture.run_ok(&[&args[..], &["--force"]].concat());
+    assert_eq!(fs::read(fixture.path("app.orig")).unwrap(), original);
+}
+
+#[test]
+fn signal_during_validation_cancels() {
+    let fixture = Fixture::new("cancel-validation");
+    let mapping = fixture.write_mapping(MAPPING);
+    // a nix-instantiate interrupted along with nix-autobahn, as by Ctrl-C
+    let bin = fixture.path("bin");
+    fs::create_dir(&bin).unwrap();
+    let nix_instantiate = bin.join("nix-instantiate");
+    fs::write(
+        &nix_instantiate,
+        "#!/bin/sh\nkill -INT $PPID\nsleep 0.1\nexit 130\n",
+    )
+    .unwrap();
+    fs::set_permissions(&nix_instantiate, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = std::env::join_paths(std::iter::once(bin).chain(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    )))
+    .unwrap();
+
+    let output = fixture
+        .command(&fixture.path("app"))
+        .args(["--mapping-file", mapping.to_str().unwrap(), "--offline"])
+        .args(["--output-format", "nix-fmt"])
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(130),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn audio_packages_are_annotated_only_with_annotate() {
+    let fixture = Fixture::new("audio");
+    let mapping =
+        fixture.write_mapping("\"libfoo.so.1\" = \"alsa-lib\"\n\"libbar.so.2\" = \"bar\"\n");
+    let args = ["--mapping-file", mapping.to_str().unwrap(), "--audio"];
+
+    fixture.run_ok(&args);
+    let expr = fixture.expression();
+    assert!(!expr.contains("audio support"), "{}", expr);
+
+    fixture.run_ok(&[&args[..], &["--annotate"]].concat());
+    let expr = fixture.expression();
+    // alsa-lib keeps the lib it was selected for
+    assert!(
+        expr.contains("\n      alsa-lib # for libfoo.so.1; audio support (--audio flag)\n"),
+        "{}",
+        expr
+    );
+    assert!(
+        expr.contains("\n      pulseaudio # audio support (--audio flag)\n"),
+        "{}",
+        expr
+    );
+}
+
+#[test]
+fn annotations_strip_versions_with_strip_version() {
+    let fixture = Fixture::new("annotate-strip");
+    let mapping = fixture.write_mapping("\"libssl.so.1.1\" = \"openssl\"\n");
+    let ldd_output = fixture.path("ldd-output.txt");
+    fs::write(&ldd_output, "\tlibssl.so.1.1 => not found\n").unwrap();
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--ldd-output",
+        ldd_output.to_str().unwrap(),
+        "--annotate",
+    ];
+
+    fixture.run_ok(&args);
+    let expr = fixture.expression();
+    assert!(
+        expr.contains("\n      openssl # for libssl.so.1.1\n"),
+        "{}",
+        expr
+    );
+
+    fixture.run_ok(&[&args[..], &["--strip-version"]].concat());
+    let expr = fixture.expression();
+    assert!(
+        expr.contains("\n      openssl # for libssl.so.1\n"),
+        "{}",
+        expr
+    );
+    assert!(expr.contains("# missing libs: libssl.so.1\n"), "{}", expr);
+}
+
+#[test]
+fn append_reports_unresolved_libs() {
+    let fixture = Fixture::new("append");
+    let mapping = fixture.write_mapping(PARTIAL_MAPPING);
+    let shell_nix = fixture.path("shell.nix");
+    let content = "[\n  # nix-autobahn: start\n  # nix-autobahn: end\n]\n";
+    fs::write(&shell_nix, content).unwrap();
+    let args = [
+        "--mapping-file",
+        mapping.to_str().unwrap(),
+        "--append",
+        shell_nix.to_str().unwrap(),
+    ];
+
+    let output = fixture.run(&args);
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(
+        fs::read_to_string(&shell_nix).unwrap(),
+        "[\n  # nix-autobahn: start\n  foo\n  # nix-autobahn: end\n]\n"
+    );
+
+    fs::write(&shell_nix, content).unwrap();
+    let output = fixture.run(&[&args[..], &["--strict"]].concat());
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(fs::read_to_string(&shell_nix).unwrap(), content);
+}
+
+#[test]
+fn fontconfig_conflicts_with_no_profile() {
+    let fixture = Fixture::new("fontconfig");
+
+    let output = fixture.run(&["--fontconfig", "--no-profile"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}